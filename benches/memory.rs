@@ -0,0 +1,71 @@
+/*
+ * Compares the flat and sparse memory backends. Run with `cargo bench`; the benchmark
+ * uses plain timing rather than a harness so the crate keeps no dependencies.
+ */
+use clockwork_vm::assembler::assemble;
+use clockwork_vm::{ Memory, RuntimeBuilder };
+
+use std::hint::black_box;
+use std::time::{ Duration, Instant };
+
+const MEMORY_SIZE_BYTES: usize = 64 << 20;
+
+/*
+ * Writes then reads back every word of a 1 MiB array, one word at a time.
+ */
+const SWEEP: &str = "
+        load $0x100000, d0
+        load $0x200000, d1
+    loop:
+        strm d0, [d0]
+        ldm [d0], d2
+        addi d0, $8, d0
+        cmp d0, d1
+        brlt loop
+        halt
+";
+
+/*
+ * Each run times itself, so setup outside the part being compared is left out.
+ */
+fn measure<F: FnMut() -> Duration>(name: &str, iterations: u32, mut run: F) {
+    let total: Duration = (0..iterations).map(|_| run()).sum();
+    println!("{:<32} {:>12?}", name, total / iterations);
+}
+
+fn create(sparse: bool) -> Memory {
+    if sparse {
+        Memory::sparse_with_size(MEMORY_SIZE_BYTES)
+    } else {
+        Memory::new_with_size(MEMORY_SIZE_BYTES)
+    }
+}
+
+fn create_timed(sparse: bool) -> Duration {
+    let start = Instant::now();
+    let memory = black_box(create(sparse));
+    let elapsed = start.elapsed();
+    drop(memory);
+    elapsed
+}
+
+/*
+ * Only the run is timed: assembling the program and creating the memory are not.
+ */
+fn sweep(sparse: bool) -> Duration {
+    let program = assemble(SWEEP).unwrap();
+    let mut vm = RuntimeBuilder::new()
+        .with_memory(create(sparse))
+        .with_program(program)
+        .build();
+    let start = Instant::now();
+    black_box(vm.run().unwrap());
+    start.elapsed()
+}
+
+fn main() {
+    for (name, sparse) in [("flat", false), ("sparse", true)] {
+        measure(&format!("{}: create 64 MiB", name), 20, || create_timed(sparse));
+        measure(&format!("{}: sweep 1 MiB", name), 5, || sweep(sparse));
+    }
+}
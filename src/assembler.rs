@@ -0,0 +1,575 @@
+use crate::runtime::Word;
+use crate::instruction::{ Instruction, Width };
+use crate::registers::Registers;
+use crate::error::{ Error, Result };
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/*
+ * Translates assembly source into the words understood by the runtime, one instruction per
+ * line. Operands may be separated by commas and/or whitespace and everything after a ';'
+ * is a comment:
+ *
+ *     load $449, d0       ; immediate values are prefixed with '$'
+ *     strm d0, @0         ; memory addresses are prefixed with '@'
+ *     ldm  [d1+8], d2     ; or computed from a register plus an optional offset
+ *     div  d0 d1 d2 d3
+ *     halt
+ *
+ * Memory is addressed in bytes. Plain "ldm" and "strm" move whole words; suffixes select
+ * narrower accesses ("ldm.b", "ldm.h", "ldm.w", sign-extending, or "ldm.bu", "ldm.hu",
+ * "ldm.wu", zero-extending, and "strm.b", "strm.h", "strm.w"). Instruction addresses, as
+ * used by jumps and labels, count instructions rather than bytes.
+ *
+ * Numbers may be written in decimal or with a '0x'/'0b' prefix, optionally preceded by a
+ * '-'. Immediate arithmetic takes its constant in place of the second source register:
+ *
+ *     addi d0, $-1, d0
+ *     cmpi d2, $0
+ *
+ * Loads take a 46-bit signed constant. Wider words are built by following the load with
+ * "loadh", which sets the upper 18 bits:
+ *
+ *     load  $0x1234, d0
+ *     loadh $-1, d0       ; d0 = 0xFFFFC00000001234
+ *
+ * Device ports are numbered the same way: "out d0, $1" writes d0 to the device on port 1.
+ *
+ * A line may start with a label ("loop:") naming the address of the instruction that
+ * follows it. Jumps and calls accept a label in place of a register ("jnz loop"), in which
+ * case they are expanded into a load of the target address into the scratch register at
+ * followed by the jump itself. Label jumps clobber at but never disturb the program's other
+ * registers. Labels are resolved in a second pass, so they may be used before being
+ * defined.
+ *
+ * Relative branches ("brnz loop") take either a label or an explicit offset ("brnz $-3"),
+ * counted from the address of the branch itself. They need no scratch register, and code
+ * using only them can be loaded at any address.
+ */
+pub fn assemble(source: &str) -> Result<Vec<Word>> {
+    let (statements, labels) = collect_statements(source)?;
+    let mut program = Vec::new();
+    for statement in &statements {
+        for instruction in expand(statement, &labels)? {
+            program.push(encode(statement.line, instruction)?);
+        }
+    }
+    Ok(program)
+}
+
+/*
+ * Register clobbered by label jumps to hold the target address. It is a special register
+ * with no name in assembly, so programs cannot keep values in it.
+ */
+pub const SCRATCH_REGISTER: u8 = Registers::SCRATCH as u8;
+
+struct Statement<'a> {
+    line: usize,
+    address: Word,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+impl Statement<'_> {
+    fn target_label(&self) -> Option<&str> {
+        match self.operands.as_slice() {
+            [operand] if jump(self.mnemonic, 0).is_some() && is_label(operand) => Some(operand),
+            _ => None,
+        }
+    }
+
+    fn branch_label(&self) -> Option<&str> {
+        match self.operands.as_slice() {
+            [operand] if branch(self.mnemonic, 0).is_some() && is_label(operand) => Some(operand),
+            _ => None,
+        }
+    }
+
+    fn size(&self) -> Word {
+        if self.target_label().is_some() { 2 } else { 1 }
+    }
+}
+
+/*
+ * First pass: splits the source into statements and records the address of every label.
+ */
+fn collect_statements(source: &str) -> Result<(Vec<Statement<'_>>, HashMap<&str, Word>)> {
+    let mut statements = Vec::new();
+    let mut labels = HashMap::new();
+    let mut address = 0;
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut tokens = tokenize(text);
+        while let Some(label) = tokens.first().and_then(|token| token.strip_suffix(':')) {
+            if !is_label(label) {
+                return Err(Error::InvalidOperand { line, operand: tokens[0].to_string() });
+            }
+            if labels.insert(label, address).is_some() {
+                return Err(Error::DuplicateLabel { line, label: label.to_string() });
+            }
+            tokens.remove(0);
+        }
+        if let Some((mnemonic, operands)) = tokens.split_first() {
+            let statement = Statement { line, address, mnemonic, operands: operands.to_vec() };
+            address += statement.size();
+            statements.push(statement);
+        }
+    }
+    Ok((statements, labels))
+}
+
+/*
+ * Second pass: turns a statement into one or more instructions, resolving label operands.
+ */
+fn expand(statement: &Statement, labels: &HashMap<&str, Word>) -> Result<Vec<Instruction>> {
+    let line = statement.line;
+    let resolve = |label: &str| {
+        labels
+            .get(label)
+            .copied()
+            .ok_or_else(|| Error::UndefinedLabel { line, label: label.to_string() })
+    };
+    if let Some(label) = statement.target_label() {
+        let load = Instruction::Load { value: resolve(label)?, dest_reg: SCRATCH_REGISTER };
+        return Ok(vec![load, jump(statement.mnemonic, SCRATCH_REGISTER).unwrap()]);
+    }
+    if let Some(label) = statement.branch_label() {
+        let offset = resolve(label)? - statement.address;
+        return Ok(vec![branch(statement.mnemonic, offset).unwrap()]);
+    }
+    parse_instruction(line, statement.mnemonic, &statement.operands).map(|instruction| vec![instruction])
+}
+
+/*
+ * Values that do not fit their field are only caught when encoding, so the error names the
+ * field and the value as it was encoded (the address, for a label) along with the line.
+ */
+fn encode(line: usize, instruction: Instruction) -> Result<Word> {
+    instruction.encode().map_err(|error| match error {
+        Error::OperandOutOfRange { field, value, bits, .. } => Error::OperandOutOfRange { line: Some(line), field, value, bits },
+        error => error,
+    })
+}
+
+/*
+ * Separators inside brackets do not split tokens, so "[d1 + 8]" stays a single operand.
+ */
+fn tokenize(text: &str) -> Vec<&str> {
+    let code = match text.find(';') {
+        Some(comment_start) => &text[..comment_start],
+        None => text,
+    };
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut in_brackets = false;
+    for (index, c) in code.char_indices() {
+        let separator = !in_brackets && (c == ',' || c.is_whitespace());
+        match c {
+            '[' => in_brackets = true,
+            ']' => in_brackets = false,
+            _ => {},
+        }
+        match (separator, start) {
+            (true, Some(token_start)) => {
+                tokens.push(&code[token_start..index]);
+                start = None;
+            },
+            (false, None) => start = Some(index),
+            _ => {},
+        }
+    }
+    if let Some(token_start) = start {
+        tokens.push(&code[token_start..]);
+    }
+    tokens
+}
+
+fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid_start = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    valid_start
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && Registers::index_of(name).is_none()
+}
+
+fn jump(mnemonic: &str, src: u8) -> Option<Instruction> {
+    match mnemonic.to_lowercase().as_str() {
+        "jmp"  => Some(Instruction::Jmp { src }),
+        "jz"   => Some(Instruction::Jz { src }),
+        "jnz"  => Some(Instruction::Jnz { src }),
+        "jgt"  => Some(Instruction::Jgt { src }),
+        "jlt"  => Some(Instruction::Jlt { src }),
+        "jge"  => Some(Instruction::Jge { src }),
+        "jle"  => Some(Instruction::Jle { src }),
+        "ja"   => Some(Instruction::Ja { src }),
+        "jb"   => Some(Instruction::Jb { src }),
+        "jo"   => Some(Instruction::Jo { src }),
+        "js"   => Some(Instruction::Js { src }),
+        "call" => Some(Instruction::Call { src }),
+        _      => None,
+    }
+}
+
+fn branch(mnemonic: &str, offset: Word) -> Option<Instruction> {
+    match mnemonic.to_lowercase().as_str() {
+        "br"   => Some(Instruction::Br { offset }),
+        "brz"  => Some(Instruction::Brz { offset }),
+        "brnz" => Some(Instruction::Brnz { offset }),
+        "brgt" => Some(Instruction::Brgt { offset }),
+        "brlt" => Some(Instruction::Brlt { offset }),
+        "brge" => Some(Instruction::Brge { offset }),
+        "brle" => Some(Instruction::Brle { offset }),
+        "bra"  => Some(Instruction::Bra { offset }),
+        "brb"  => Some(Instruction::Brb { offset }),
+        "bro"  => Some(Instruction::Bro { offset }),
+        "brs"  => Some(Instruction::Brs { offset }),
+        _      => None,
+    }
+}
+
+/*
+ * Splits a load or store mnemonic into its base and access: "ldm.hu" loads a half word and
+ * zero-extends it. Without a suffix whole words are moved.
+ */
+fn memory_access(mnemonic: &str) -> Option<(&str, Width, bool)> {
+    let (base, suffix) = mnemonic.split_once('.').unwrap_or((mnemonic, Width::B8.suffix()));
+    let (suffix, unsigned) = suffix.strip_suffix('u').map_or((suffix, false), |suffix| (suffix, true));
+    let width = Width::from_suffix(suffix).filter(|width| !unsigned || *width != Width::B8)?;
+    match base {
+        "ldm"               => Some((base, width, unsigned)),
+        "strm" if !unsigned => Some((base, width, unsigned)),
+        _                   => None,
+    }
+}
+
+fn parse_instruction(line: usize, mnemonic: &str, operands: &[&str]) -> Result<Instruction> {
+    let expect = |count: usize| {
+        if operands.len() == count {
+            Ok(())
+        } else {
+            Err(Error::WrongOperandCount { line, expected: count, found: operands.len() })
+        }
+    };
+    let reg = |index: usize| parse_register(line, operands[index]);
+    let imm = |index: usize| parse_prefixed(line, operands[index], '$');
+    let port = |index: usize| {
+        imm(index).and_then(|number| {
+            u16::try_from(number).map_err(|_| Error::InvalidOperand { line, operand: operands[index].to_string() })
+        })
+    };
+
+    match mnemonic.to_lowercase().as_str() {
+        "halt" => expect(0).map(|()| Instruction::Halt),
+        "load" => {
+            expect(2)?;
+            let value = parse_prefixed(line, operands[0], '$')?;
+            Ok(Instruction::Load { value, dest_reg: reg(1)? })
+        },
+        "loadh" => {
+            expect(2)?;
+            Ok(Instruction::LoadHigh { value: imm(0)?, dest_reg: reg(1)? })
+        },
+        "copy" => expect(2).and_then(|()| Ok(Instruction::Copy { src: reg(0)?, dest: reg(1)? })),
+        "add"  => expect(3).and_then(|()| Ok(Instruction::Add { src1: reg(0)?, src2: reg(1)?, dest: reg(2)? })),
+        "sub"  => expect(3).and_then(|()| Ok(Instruction::Sub { src1: reg(0)?, src2: reg(1)?, dest: reg(2)? })),
+        "mult" => expect(3).and_then(|()| Ok(Instruction::Mult { src1: reg(0)?, src2: reg(1)?, dest: reg(2)? })),
+        "div"  => expect(4).and_then(|()| Ok(Instruction::Div { src1: reg(0)?, src2: reg(1)?, quot_dest: reg(2)?, rem_dest: reg(3)? })),
+        "cmp"  => expect(2).and_then(|()| Ok(Instruction::Cmp { src1: reg(0)?, src2: reg(1)? })),
+        "inc"  => expect(1).and_then(|()| Ok(Instruction::Inc { dest: reg(0)? })),
+        "dec"  => expect(1).and_then(|()| Ok(Instruction::Dec { dest: reg(0)? })),
+        "push" => expect(1).and_then(|()| Ok(Instruction::Push { src: reg(0)? })),
+        "pop"  => expect(1).and_then(|()| Ok(Instruction::Pop { dest: reg(0)? })),
+        "ret"  => expect(0).map(|()| Instruction::Ret),
+        "tret" => expect(0).map(|()| Instruction::TrapReturn),
+        "and"  => expect(3).and_then(|()| Ok(Instruction::And { src1: reg(0)?, src2: reg(1)?, dest: reg(2)? })),
+        "or"   => expect(3).and_then(|()| Ok(Instruction::Or { src1: reg(0)?, src2: reg(1)?, dest: reg(2)? })),
+        "xor"  => expect(3).and_then(|()| Ok(Instruction::Xor { src1: reg(0)?, src2: reg(1)?, dest: reg(2)? })),
+        "not"  => expect(2).and_then(|()| Ok(Instruction::Not { src: reg(0)?, dest: reg(1)? })),
+        "shl"  => expect(3).and_then(|()| Ok(Instruction::Shl { src1: reg(0)?, src2: reg(1)?, dest: reg(2)? })),
+        "shr"  => expect(3).and_then(|()| Ok(Instruction::Shr { src1: reg(0)?, src2: reg(1)?, dest: reg(2)? })),
+        "sar"  => expect(3).and_then(|()| Ok(Instruction::Sar { src1: reg(0)?, src2: reg(1)?, dest: reg(2)? })),
+        "addi" => expect(3).and_then(|()| Ok(Instruction::AddI { src: reg(0)?, imm: imm(1)?, dest: reg(2)? })),
+        "subi" => expect(3).and_then(|()| Ok(Instruction::SubI { src: reg(0)?, imm: imm(1)?, dest: reg(2)? })),
+        "muli" => expect(3).and_then(|()| Ok(Instruction::MultI { src: reg(0)?, imm: imm(1)?, dest: reg(2)? })),
+        "cmpi" => expect(2).and_then(|()| Ok(Instruction::CmpI { src: reg(0)?, imm: imm(1)? })),
+        "in"   => expect(2).and_then(|()| Ok(Instruction::In { port: port(0)?, dest: reg(1)? })),
+        "out"  => expect(2).and_then(|()| Ok(Instruction::Out { src: reg(0)?, port: port(1)? })),
+        name if memory_access(name).is_some() => {
+            expect(2)?;
+            match memory_access(name).unwrap() {
+                ("ldm", width, unsigned) => match parse_indexed(line, operands[0])? {
+                    Some((base, offset)) => Ok(Instruction::LoadMemIndexed { base, offset, dest_reg: reg(1)?, width, unsigned }),
+                    None => {
+                        let src_addr = parse_prefixed(line, operands[0], '@')?;
+                        Ok(Instruction::LoadMem { src_addr, dest_reg: reg(1)?, width, unsigned })
+                    },
+                },
+                (_, width, _) => match parse_indexed(line, operands[1])? {
+                    Some((base, offset)) => Ok(Instruction::StoreMemIndexed { src_reg: reg(0)?, base, offset, width }),
+                    None => {
+                        let dest_addr = parse_prefixed(line, operands[1], '@')?;
+                        Ok(Instruction::StoreMem { src_reg: reg(0)?, dest_addr, width })
+                    },
+                },
+            }
+        },
+        name if jump(name, 0).is_some() => expect(1).and_then(|()| Ok(jump(name, reg(0)?).unwrap())),
+        name if branch(name, 0).is_some() => expect(1).and_then(|()| Ok(branch(name, imm(0)?).unwrap())),
+        _ => Err(Error::UnknownMnemonic { line, mnemonic: mnemonic.to_string() }),
+    }
+}
+
+fn parse_register(line: usize, operand: &str) -> Result<u8> {
+    Registers::index_of(operand)
+        .map(|index| index as u8)
+        .ok_or_else(|| Error::InvalidOperand { line, operand: operand.to_string() })
+}
+
+/*
+ * Parses "[reg]", "[reg+offset]" or "[reg-offset]", returning None for operands that are
+ * not bracketed at all.
+ */
+fn parse_indexed(line: usize, operand: &str) -> Result<Option<(u8, Word)>> {
+    let invalid = || Error::InvalidOperand { line, operand: operand.to_string() };
+    let inner = match operand.strip_prefix('[') {
+        Some(rest) => rest.strip_suffix(']').ok_or_else(invalid)?,
+        None => return Ok(None),
+    };
+    let inner: String = inner.chars().filter(|c| !c.is_whitespace()).collect();
+    let (base, offset) = match inner.find(['+', '-']) {
+        Some(sign) => {
+            let magnitude = inner[sign + 1..].to_string();
+            let offset = parse_number(&magnitude).filter(|_| !magnitude.starts_with('-')).ok_or_else(invalid)?;
+            (&inner[..sign], if inner[sign..].starts_with('-') { -offset } else { offset })
+        },
+        None => (inner.as_str(), 0),
+    };
+    let base = Registers::index_of(base).ok_or_else(invalid)?;
+    Ok(Some((base as u8, offset)))
+}
+
+fn parse_prefixed(line: usize, operand: &str, prefix: char) -> Result<Word> {
+    let invalid = || Error::InvalidOperand { line, operand: operand.to_string() };
+    if !operand.starts_with(prefix) {
+        return Err(invalid());
+    }
+    parse_number(&operand[prefix.len_utf8()..])
+        .ok_or_else(invalid)
+}
+
+fn parse_number(text: &str) -> Option<Word> {
+    if let Some(magnitude) = text.strip_prefix('-').filter(|magnitude| !magnitude.starts_with('-')) {
+        return parse_number(magnitude).map(|value| -value);
+    }
+    let lowercase = text.to_lowercase();
+    let (digits, radix) = if let Some(hex) = lowercase.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(bin) = lowercase.strip_prefix("0b") {
+        (bin, 2)
+    } else {
+        (lowercase.as_str(), 10)
+    };
+    Word::from_str_radix(&digits.replace('_', ""), radix).ok()
+}
+
+#[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)] // binary literals are grouped by instruction field
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_the_same_words_as_hand_encoded_programs() {
+        let source = "
+            load $13, d0
+            copy d0, d1
+            add d0 d1 d3
+            div d0 d1 d2 d3     ; quotient and remainder
+            cmp d0, d2
+            jnz d3
+            strm d0, @0
+            ldm @0, d1
+            halt
+        ";
+        let expected = vec![
+            0b00000000_0000000000000000000000000000000000000000001101_0000000001i64,
+            0b000000000000000000000000001_000000000000000000000000000_0000001100i64,
+            0b000000000000000011_000000000000000001_000000000000000000_0000000010i64,
+            0b000000000000011_0000000000010_0000000000001_0000000000000_0000001011i64,
+            0b000000000000000000000000010_000000000000000000000000000_0000000101i64,
+            0b000000000000000000000000000000000000000000000000000011_0000001000i64,
+            0b000000000000000000000000000_000000000000000000000000000_0000010000i64,
+            0b000000000000000000000000001_000000000000000000000000000_0000001111i64,
+            0b0000000000000000000000000000000000000000000000000000000000000000i64,
+        ];
+
+        assert_eq!(expected, assemble(source).unwrap());
+    }
+
+    #[test]
+    fn numbers_can_be_written_in_other_bases() {
+        let program = assemble("load $0x1F, d1\nload $0b101, d2\nstrm d1, @0x10").unwrap();
+        assert_eq!(Instruction::Load { value: 31, dest_reg: 1 }, Instruction::from(program[0]));
+        assert_eq!(Instruction::Load { value: 5, dest_reg: 2 }, Instruction::from(program[1]));
+        assert_eq!(Instruction::StoreMem { src_reg: 1, dest_addr: 16, width: Width::B8 }, Instruction::from(program[2]));
+    }
+
+    #[test]
+    fn immediate_arithmetic_takes_signed_constants() {
+        let program = assemble("addi d0, $-1, d1\nsubi d1, $0x10, d2\nmuli d2, $3, d3\ncmpi d3, $-0b11").unwrap();
+        assert_eq!(Instruction::AddI { src: 0, imm: -1, dest: 1 }, Instruction::from(program[0]));
+        assert_eq!(Instruction::SubI { src: 1, imm: 16, dest: 2 }, Instruction::from(program[1]));
+        assert_eq!(Instruction::MultI { src: 2, imm: 3, dest: 3 }, Instruction::from(program[2]));
+        assert_eq!(Instruction::CmpI { src: 3, imm: -3 }, Instruction::from(program[3]));
+
+        assert!(matches!(assemble("addi d0, d1, d2"), Err(Error::InvalidOperand { line: 1, .. })));
+        assert!(matches!(assemble("cmpi d0, $137438953472"), Err(Error::OperandOutOfRange { line: Some(1), field: "imm", .. })));
+    }
+
+    #[test]
+    fn bracketed_operands_use_indexed_addressing() {
+        let program = assemble("ldm [d1], d0\nstrm d0, [d1+8]\nldm [ sp - 0x2 ], d3\nstrm d2 @4").unwrap();
+        let word = Width::B8;
+        assert_eq!(Instruction::LoadMemIndexed { base: 1, offset: 0, dest_reg: 0, width: word, unsigned: false }, Instruction::from(program[0]));
+        assert_eq!(Instruction::StoreMemIndexed { src_reg: 0, base: 1, offset: 8, width: word }, Instruction::from(program[1]));
+        let sp = Registers::STACK_POINTER as u8;
+        assert_eq!(Instruction::LoadMemIndexed { base: sp, offset: -2, dest_reg: 3, width: word, unsigned: false }, Instruction::from(program[2]));
+        assert_eq!(Instruction::StoreMem { src_reg: 2, dest_addr: 4, width: word }, Instruction::from(program[3]));
+
+        assert!(matches!(assemble("ldm [d1, d0"), Err(Error::WrongOperandCount { line: 1, .. })));
+        assert!(matches!(assemble("ldm [x1+2], d0"), Err(Error::InvalidOperand { line: 1, .. })));
+        assert!(matches!(assemble("ldm [d1+-2], d0"), Err(Error::InvalidOperand { line: 1, .. })));
+        assert!(matches!(assemble("strm d0, [d1+]"), Err(Error::InvalidOperand { line: 1, .. })));
+    }
+
+    #[test]
+    fn port_numbers_must_fit_in_sixteen_bits() {
+        let program = assemble("in $1, d0\nout d0, $0xFFFF").unwrap();
+        assert_eq!(Instruction::In { port: 1, dest: 0 }, Instruction::from(program[0]));
+        assert_eq!(Instruction::Out { src: 0, port: 65535 }, Instruction::from(program[1]));
+
+        assert!(matches!(assemble("out d0, $65536"), Err(Error::InvalidOperand { line: 1, .. })));
+        assert!(matches!(assemble("in $-1, d0"), Err(Error::InvalidOperand { line: 1, .. })));
+    }
+
+    #[test]
+    fn width_suffixes_select_narrow_memory_accesses() {
+        let program = assemble("ldm.bu @3, d0\nldm.h [d1+2], d2\nstrm.w d0, @4\nSTRM.B d1, [d2]\nldm.d @8, d3").unwrap();
+        assert_eq!(Instruction::LoadMem { src_addr: 3, dest_reg: 0, width: Width::B1, unsigned: true }, Instruction::from(program[0]));
+        assert_eq!(
+            Instruction::LoadMemIndexed { base: 1, offset: 2, dest_reg: 2, width: Width::B2, unsigned: false },
+            Instruction::from(program[1])
+        );
+        assert_eq!(Instruction::StoreMem { src_reg: 0, dest_addr: 4, width: Width::B4 }, Instruction::from(program[2]));
+        assert_eq!(Instruction::StoreMemIndexed { src_reg: 1, base: 2, offset: 0, width: Width::B1 }, Instruction::from(program[3]));
+        assert_eq!(Instruction::LoadMem { src_addr: 8, dest_reg: 3, width: Width::B8, unsigned: false }, Instruction::from(program[4]));
+
+        assert!(matches!(assemble("strm.bu d0, @0"), Err(Error::UnknownMnemonic { line: 1, .. })));
+        assert!(matches!(assemble("ldm.q @0, d0"), Err(Error::UnknownMnemonic { line: 1, .. })));
+        assert!(matches!(assemble("add.b d0, d1, d2"), Err(Error::UnknownMnemonic { line: 1, .. })));
+    }
+
+    #[test]
+    fn blank_lines_and_comments_are_skipped() {
+        let program = assemble("; nothing here\n\n   \nhalt ; stop").unwrap();
+        assert_eq!(vec![0], program);
+    }
+
+    #[test]
+    fn unknown_mnemonics_are_reported_with_their_line() {
+        let error = assemble("halt\nfrobnicate d0").unwrap_err();
+        assert!(matches!(error, Error::UnknownMnemonic { line: 2, ref mnemonic } if mnemonic == "frobnicate"));
+    }
+
+    #[test]
+    fn label_jumps_expand_into_a_load_and_a_register_jump() {
+        let source = "
+            start:
+                inc d0
+                jmp start
+        ";
+        let program = assemble(source).unwrap();
+        let expected = vec![
+            Instruction::Inc { dest: 0 },
+            Instruction::Load { value: 0, dest_reg: SCRATCH_REGISTER },
+            Instruction::Jmp { src: SCRATCH_REGISTER },
+        ];
+        assert_eq!(expected, program.into_iter().map(Instruction::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn forward_references_account_for_expanded_jumps() {
+        let source = "
+                jz done         ; expands to two words
+                jnz d1
+                jgt done
+            done: halt
+        ";
+        let program: Vec<Instruction> = assemble(source).unwrap().into_iter().map(Instruction::from).collect();
+        assert_eq!(Instruction::Load { value: 5, dest_reg: SCRATCH_REGISTER }, program[0]);
+        assert_eq!(Instruction::Jnz { src: 1 }, program[2]);
+        assert_eq!(Instruction::Load { value: 5, dest_reg: SCRATCH_REGISTER }, program[3]);
+        assert_eq!(Instruction::Halt, program[5]);
+    }
+
+    #[test]
+    fn branch_labels_resolve_to_offsets_from_the_branch() {
+        let source = "
+                brz done        ; never expanded
+            back:
+                jz back         ; two words
+                brnz back
+                br $-1
+            done: halt
+        ";
+        let program: Vec<Instruction> = assemble(source).unwrap().into_iter().map(Instruction::from).collect();
+        assert_eq!(Instruction::Brz { offset: 5 }, program[0]);
+        assert_eq!(Instruction::Brnz { offset: -2 }, program[3]);
+        assert_eq!(Instruction::Br { offset: -1 }, program[4]);
+        assert_eq!(Instruction::Halt, program[5]);
+
+        assert!(matches!(assemble("brz nowhere"), Err(Error::UndefinedLabel { line: 1, .. })));
+        assert!(matches!(assemble("br d0"), Err(Error::InvalidOperand { line: 1, .. })));
+    }
+
+    #[test]
+    fn undefined_and_duplicate_labels_are_rejected() {
+        let error = assemble("jmp nowhere").unwrap_err();
+        assert!(matches!(error, Error::UndefinedLabel { line: 1, ref label } if label == "nowhere"));
+
+        let error = assemble("here: halt\nhere: halt").unwrap_err();
+        assert!(matches!(error, Error::DuplicateLabel { line: 2, ref label } if label == "here"));
+
+        assert!(matches!(assemble("d0: halt"), Err(Error::InvalidOperand { line: 1, .. })));
+    }
+
+    #[test]
+    fn malformed_operands_are_rejected() {
+        assert!(matches!(assemble("load 13, d0"), Err(Error::InvalidOperand { line: 1, .. })));
+        assert!(matches!(assemble("copy d0, x1"), Err(Error::InvalidOperand { line: 1, .. })));
+        assert!(matches!(assemble("ldm $0, d1"), Err(Error::InvalidOperand { line: 1, .. })));
+        let error = assemble("load $0x2000_0000_0000, d0").unwrap_err();
+        assert_eq!(Error::OperandOutOfRange { line: Some(1), field: "value", value: 1 << 45, bits: 46 }, error);
+        assert_eq!("line 1: operand value = 35184372088832 does not fit in 46 bits", error.to_string());
+        assert!(matches!(assemble("loadh $131072, d0"), Err(Error::OperandOutOfRange { line: Some(1), field: "value", .. })));
+        assert!(matches!(assemble("halt\nstrm d0, @134217728"), Err(Error::OperandOutOfRange { line: Some(2), field: "dest_addr", .. })));
+        assert!(matches!(assemble("add d0, d1"), Err(Error::WrongOperandCount { line: 1, expected: 3, found: 2 })));
+    }
+
+    #[test]
+    fn label_jumps_leave_program_registers_alone() {
+        let source = "
+                load $7, d3
+                jmp next
+            next:
+                halt
+        ";
+        let program: Vec<Instruction> = assemble(source).unwrap().into_iter().map(Instruction::from).collect();
+        assert_eq!(Instruction::Load { value: 3, dest_reg: Registers::SCRATCH as u8 }, program[1]);
+        assert_eq!(Instruction::Jmp { src: Registers::SCRATCH as u8 }, program[2]);
+
+        let load = Instruction::Load { value: 1, dest_reg: SCRATCH_REGISTER };
+        assert_eq!(vec![load.encode().unwrap()], assemble("load $1, at").unwrap());
+        assert!(matches!(assemble("at: halt"), Err(Error::InvalidOperand { line: 1, .. })));
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+/*
+ * Storage behind a `Memory`. Memory does the bounds checking, so backends are only ever
+ * asked for bytes inside 0..size(). Backends must be Send so a runtime can be moved to
+ * another thread.
+ */
+pub trait MemoryBackend: Send {
+    fn size(&self) -> usize;
+    fn read(&self, address: usize, buffer: &mut [u8]);
+    fn write(&mut self, address: usize, data: &[u8]);
+}
+
+/*
+ * One contiguous buffer allocated up front: the fastest backend, but the whole address
+ * space costs host memory.
+ */
+pub struct Flat {
+    buffer: Vec<u8>,
+}
+
+impl Flat {
+    pub fn new(size_bytes: usize) -> Self {
+        Flat { buffer: vec![0; size_bytes] }
+    }
+}
+
+impl MemoryBackend for Flat {
+    fn size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn read(&self, address: usize, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&self.buffer[address..address + buffer.len()]);
+    }
+
+    fn write(&mut self, address: usize, data: &[u8]) {
+        self.buffer[address..address + data.len()].copy_from_slice(data);
+    }
+}
+
+/*
+ * Address space split into pages that are only allocated when first written; pages never
+ * written read as zeros. Host memory use grows with what a program touches rather than
+ * with the size of its address space.
+ */
+pub struct Sparse {
+    size_bytes: usize,
+    pages: HashMap<usize, Box<[u8; Sparse::PAGE_SIZE]>>,
+}
+
+impl Sparse {
+    pub const PAGE_SIZE: usize = 4096;
+
+    pub fn new(size_bytes: usize) -> Self {
+        Sparse { size_bytes, pages: HashMap::new() }
+    }
+
+    pub fn allocated_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /*
+     * Splits `length` bytes at `address` into (page, offset in page, offset in the access,
+     * length) pieces that each stay within one page.
+     */
+    fn pieces(address: usize, length: usize) -> impl Iterator<Item = (usize, usize, usize, usize)> {
+        let mut done = 0;
+        std::iter::from_fn(move || {
+            if done == length {
+                return None;
+            }
+            let current = address + done;
+            let offset = current % Self::PAGE_SIZE;
+            let piece = (Self::PAGE_SIZE - offset).min(length - done);
+            let start = done;
+            done += piece;
+            Some((current / Self::PAGE_SIZE, offset, start, piece))
+        })
+    }
+}
+
+impl MemoryBackend for Sparse {
+    fn size(&self) -> usize {
+        self.size_bytes
+    }
+
+    fn read(&self, address: usize, buffer: &mut [u8]) {
+        for (page, offset, start, length) in Self::pieces(address, buffer.len()) {
+            let destination = &mut buffer[start..start + length];
+            match self.pages.get(&page) {
+                Some(bytes) => destination.copy_from_slice(&bytes[offset..offset + length]),
+                None => destination.fill(0),
+            }
+        }
+    }
+
+    fn write(&mut self, address: usize, data: &[u8]) {
+        for (page, offset, start, length) in Self::pieces(address, data.len()) {
+            self.pages
+                .entry(page)
+                .or_insert_with(|| Box::new([0; Self::PAGE_SIZE]))[offset..offset + length]
+                .copy_from_slice(&data[start..start + length]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_pages_are_allocated_on_first_write() {
+        let mut sparse = Sparse::new(1 << 40);
+        let mut buffer = [0xFF; 4];
+        sparse.read(1 << 39, &mut buffer);
+        assert_eq!([0; 4], buffer);
+        assert_eq!(0, sparse.allocated_pages());
+
+        sparse.write(Sparse::PAGE_SIZE - 2, &[1, 2, 3, 4]);
+        assert_eq!(2, sparse.allocated_pages());
+        sparse.read(Sparse::PAGE_SIZE - 3, &mut buffer);
+        assert_eq!([0, 1, 2, 3], buffer);
+    }
+
+    #[test]
+    fn backends_agree() {
+        let mut flat = Flat::new(3 * Sparse::PAGE_SIZE);
+        let mut sparse = Sparse::new(3 * Sparse::PAGE_SIZE);
+        let data: Vec<u8> = (0..=255).cycle().take(Sparse::PAGE_SIZE + 100).collect();
+        for backend in [&mut flat as &mut dyn MemoryBackend, &mut sparse] {
+            backend.write(Sparse::PAGE_SIZE - 50, &data);
+            backend.write(7, &[9; 3]);
+        }
+
+        let (mut from_flat, mut from_sparse) = (vec![0; flat.size()], vec![0; sparse.size()]);
+        flat.read(0, &mut from_flat);
+        sparse.read(0, &mut from_sparse);
+        assert_eq!(from_flat, from_sparse);
+    }
+}
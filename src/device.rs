@@ -0,0 +1,115 @@
+use crate::runtime::Word;
+
+use std::collections::VecDeque;
+use std::io::{ Read, Result, Write };
+use std::sync::{ Arc, Mutex };
+
+/*
+ * Something a guest program can talk to through a numbered port with the In and Out
+ * instructions. Each instruction transfers a single word; a failing transfer faults the
+ * instruction with `Error::DeviceFailure`.
+ */
+pub trait Device {
+    fn read(&mut self) -> Result<Word>;
+    fn write(&mut self, value: Word) -> Result<()>;
+}
+
+/*
+ * Word returned by character devices once their input is exhausted.
+ */
+pub const END_OF_INPUT: Word = -1;
+
+/*
+ * Character device over the host's stdin and stdout. Writes output the low byte of the
+ * word; reads return one byte of input, or END_OF_INPUT.
+ */
+#[derive(Default)]
+pub struct Console;
+
+impl Console {
+    pub fn new() -> Self {
+        Console
+    }
+}
+
+impl Device for Console {
+    fn read(&mut self) -> Result<Word> {
+        let mut byte = [0];
+        std::io::stdin()
+            .read(&mut byte)
+            .map(|count| if count == 0 { END_OF_INPUT } else { byte[0] as Word })
+    }
+
+    fn write(&mut self, value: Word) -> Result<()> {
+        let mut stdout = std::io::stdout();
+        stdout
+            .write_all(&[value as u8])
+            .and_then(|()| stdout.flush())
+    }
+}
+
+/*
+ * In-memory device for tests and embedding: reads are served from a queue of words fixed
+ * up front, and every written word is recorded. Clones share the same buffers, so a clone
+ * kept by the host can inspect the output after the original was moved into a runtime,
+ * even one running on another thread.
+ */
+#[derive(Default, Clone)]
+pub struct Capture {
+    input: Arc<Mutex<VecDeque<Word>>>,
+    output: Arc<Mutex<Vec<Word>>>,
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_input(self, input: &[Word]) -> Self {
+        self.input.lock().unwrap().extend(input);
+        self
+    }
+
+    pub fn output(&self) -> Vec<Word> {
+        self.output.lock().unwrap().clone()
+    }
+
+    /*
+     * The output as text, taking the low byte of every word like the console does.
+     */
+    pub fn output_string(&self) -> String {
+        let bytes: Vec<u8> = self.output.lock().unwrap().iter().map(|word| *word as u8).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Device for Capture {
+    fn read(&mut self) -> Result<Word> {
+        Ok(self.input.lock().unwrap().pop_front().unwrap_or(END_OF_INPUT))
+    }
+
+    fn write(&mut self, value: Word) -> Result<()> {
+        self.output.lock().unwrap().push(value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_replays_input_and_records_output() {
+        let capture = Capture::new().with_input(&[7, 8]);
+        let mut device = capture.clone();
+
+        assert_eq!(7, device.read().unwrap());
+        assert_eq!(8, device.read().unwrap());
+        assert_eq!(END_OF_INPUT, device.read().unwrap());
+
+        device.write('h' as Word).unwrap();
+        device.write('i' as Word).unwrap();
+        assert_eq!(vec![104, 105], capture.output());
+        assert_eq!("hi", capture.output_string());
+    }
+}
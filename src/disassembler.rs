@@ -0,0 +1,191 @@
+use crate::runtime::Word;
+use crate::instruction::{ Instruction, Width };
+use crate::memory::Memory;
+use crate::registers::Registers;
+use crate::error::Result;
+
+use std::fmt;
+use std::ops::Range;
+
+/*
+ * Instructions are printed with the same syntax accepted by the assembler, so a listing
+ * can be fed back into it.
+ */
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Illegal                                 => write!(f, "illegal"),
+            Instruction::Halt                                    => write!(f, "halt"),
+            Instruction::Load { value, dest_reg }                => write!(f, "load ${}, {}", value, Reg(dest_reg)),
+            Instruction::LoadMem { src_addr, dest_reg, width, unsigned } =>
+                write!(f, "{} @{}, {}", MemoryAccess("ldm", width, unsigned), src_addr, Reg(dest_reg)),
+            Instruction::StoreMem { src_reg, dest_addr, width } =>
+                write!(f, "{} {}, @{}", MemoryAccess("strm", width, false), Reg(src_reg), dest_addr),
+            Instruction::Copy { src, dest }                      => write!(f, "copy {}, {}", Reg(src), Reg(dest)),
+            Instruction::Add { src1, src2, dest }                => write!(f, "add {}, {}, {}", Reg(src1), Reg(src2), Reg(dest)),
+            Instruction::Sub { src1, src2, dest }                => write!(f, "sub {}, {}, {}", Reg(src1), Reg(src2), Reg(dest)),
+            Instruction::Mult { src1, src2, dest }               => write!(f, "mult {}, {}, {}", Reg(src1), Reg(src2), Reg(dest)),
+            Instruction::Div { src1, src2, quot_dest, rem_dest } => write!(f, "div {}, {}, {}, {}", Reg(src1), Reg(src2), Reg(quot_dest), Reg(rem_dest)),
+            Instruction::Cmp { src1, src2 }                      => write!(f, "cmp {}, {}", Reg(src1), Reg(src2)),
+            Instruction::Jmp { src }                             => write!(f, "jmp {}", Reg(src)),
+            Instruction::Jz { src }                              => write!(f, "jz {}", Reg(src)),
+            Instruction::Jnz { src }                             => write!(f, "jnz {}", Reg(src)),
+            Instruction::Jgt { src }                             => write!(f, "jgt {}", Reg(src)),
+            Instruction::Jlt { src }                             => write!(f, "jlt {}", Reg(src)),
+            Instruction::Inc { dest }                            => write!(f, "inc {}", Reg(dest)),
+            Instruction::Dec { dest }                            => write!(f, "dec {}", Reg(dest)),
+            Instruction::Push { src }                            => write!(f, "push {}", Reg(src)),
+            Instruction::Pop { dest }                            => write!(f, "pop {}", Reg(dest)),
+            Instruction::Call { src }                            => write!(f, "call {}", Reg(src)),
+            Instruction::Ret                                     => write!(f, "ret"),
+            Instruction::And { src1, src2, dest }                => write!(f, "and {}, {}, {}", Reg(src1), Reg(src2), Reg(dest)),
+            Instruction::Or { src1, src2, dest }                 => write!(f, "or {}, {}, {}", Reg(src1), Reg(src2), Reg(dest)),
+            Instruction::Xor { src1, src2, dest }                => write!(f, "xor {}, {}, {}", Reg(src1), Reg(src2), Reg(dest)),
+            Instruction::Not { src, dest }                       => write!(f, "not {}, {}", Reg(src), Reg(dest)),
+            Instruction::Shl { src1, src2, dest }                => write!(f, "shl {}, {}, {}", Reg(src1), Reg(src2), Reg(dest)),
+            Instruction::Shr { src1, src2, dest }                => write!(f, "shr {}, {}, {}", Reg(src1), Reg(src2), Reg(dest)),
+            Instruction::Sar { src1, src2, dest }                => write!(f, "sar {}, {}, {}", Reg(src1), Reg(src2), Reg(dest)),
+            Instruction::Jge { src }                             => write!(f, "jge {}", Reg(src)),
+            Instruction::Jle { src }                             => write!(f, "jle {}", Reg(src)),
+            Instruction::Ja { src }                              => write!(f, "ja {}", Reg(src)),
+            Instruction::Jb { src }                              => write!(f, "jb {}", Reg(src)),
+            Instruction::Jo { src }                              => write!(f, "jo {}", Reg(src)),
+            Instruction::Js { src }                              => write!(f, "js {}", Reg(src)),
+            Instruction::AddI { src, imm, dest }                 => write!(f, "addi {}, ${}, {}", Reg(src), imm, Reg(dest)),
+            Instruction::SubI { src, imm, dest }                 => write!(f, "subi {}, ${}, {}", Reg(src), imm, Reg(dest)),
+            Instruction::MultI { src, imm, dest }                => write!(f, "muli {}, ${}, {}", Reg(src), imm, Reg(dest)),
+            Instruction::CmpI { src, imm }                       => write!(f, "cmpi {}, ${}", Reg(src), imm),
+            Instruction::Br { offset }                           => write!(f, "br ${}", offset),
+            Instruction::Brz { offset }                          => write!(f, "brz ${}", offset),
+            Instruction::Brnz { offset }                         => write!(f, "brnz ${}", offset),
+            Instruction::Brgt { offset }                         => write!(f, "brgt ${}", offset),
+            Instruction::Brlt { offset }                         => write!(f, "brlt ${}", offset),
+            Instruction::Brge { offset }                         => write!(f, "brge ${}", offset),
+            Instruction::Brle { offset }                         => write!(f, "brle ${}", offset),
+            Instruction::Bra { offset }                          => write!(f, "bra ${}", offset),
+            Instruction::Brb { offset }                          => write!(f, "brb ${}", offset),
+            Instruction::Bro { offset }                          => write!(f, "bro ${}", offset),
+            Instruction::Brs { offset }                          => write!(f, "brs ${}", offset),
+            Instruction::LoadHigh { value, dest_reg }            => write!(f, "loadh ${}, {}", value, Reg(dest_reg)),
+            Instruction::TrapReturn                              => write!(f, "tret"),
+            Instruction::In { port, dest }                       => write!(f, "in ${}, {}", port, Reg(dest)),
+            Instruction::Out { src, port }                       => write!(f, "out {}, ${}", Reg(src), port),
+            Instruction::LoadMemIndexed { base, offset, dest_reg, width, unsigned } =>
+                write!(f, "{} {}, {}", MemoryAccess("ldm", width, unsigned), Indexed(base, offset), Reg(dest_reg)),
+            Instruction::StoreMemIndexed { src_reg, base, offset, width } =>
+                write!(f, "{} {}, {}", MemoryAccess("strm", width, false), Reg(src_reg), Indexed(base, offset)),
+        }
+    }
+}
+
+struct Reg(u8);
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&Registers::name_of(self.0 as usize))
+    }
+}
+
+/*
+ * Memory mnemonic with its width suffix; whole-word accesses are printed without one.
+ */
+struct MemoryAccess(&'static str, Width, bool);
+
+impl fmt::Display for MemoryAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryAccess(mnemonic, Width::B8, false) => f.write_str(mnemonic),
+            MemoryAccess(mnemonic, width, unsigned) => write!(f, "{}.{}{}", mnemonic, width.suffix(), if unsigned { "u" } else { "" }),
+        }
+    }
+}
+
+struct Indexed(u8, Word);
+
+impl fmt::Display for Indexed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            0                    => write!(f, "[{}]", Reg(self.0)),
+            offset if offset < 0 => write!(f, "[{}-{}]", Reg(self.0), -offset),
+            offset               => write!(f, "[{}+{}]", Reg(self.0), offset),
+        }
+    }
+}
+
+/*
+ * Produces one line per instruction address in `addresses`, prefixed by the address. Words
+ * that do not decode to a valid instruction are shown raw so they stand out in crash dumps:
+ *
+ *      0: load $449, d0
+ *      1: <illegal 0x0000000000000200>
+ */
+pub fn disassemble(memory: &Memory, addresses: Range<usize>) -> Result<String> {
+    let mut listing = String::new();
+    for address in addresses {
+        let word = memory.read_instruction(address)?;
+        listing.push_str(&format!("{:>6}: {}\n", address, describe(word)));
+    }
+    Ok(listing)
+}
+
+fn describe(word: Word) -> String {
+    match Instruction::from(word) {
+        Instruction::Illegal => format!("<illegal {:#018x}>", word),
+        instruction => instruction.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn instructions_are_printed_in_assembler_syntax() {
+        assert_eq!("load $13, d0", Instruction::Load { value: 13, dest_reg: 0 }.to_string());
+        assert_eq!("div d0, d1, d2, d3", Instruction::Div { src1: 0, src2: 1, quot_dest: 2, rem_dest: 3 }.to_string());
+        assert_eq!("strm d0, @0", Instruction::StoreMem { src_reg: 0, dest_addr: 0, width: Width::B8 }.to_string());
+        assert_eq!("ldm @0, d1", Instruction::LoadMem { src_addr: 0, dest_reg: 1, width: Width::B8, unsigned: false }.to_string());
+        assert_eq!("ldm.hu @6, d1", Instruction::LoadMem { src_addr: 6, dest_reg: 1, width: Width::B2, unsigned: true }.to_string());
+        assert_eq!("strm.b d0, @3", Instruction::StoreMem { src_reg: 0, dest_addr: 3, width: Width::B1 }.to_string());
+        assert_eq!("copy ip, d2", Instruction::Copy { src: Registers::INSTR_POINTER as u8, dest: 2 }.to_string());
+        let load = Instruction::LoadMemIndexed { base: 1, offset: 8, dest_reg: 0, width: Width::B8, unsigned: false };
+        assert_eq!("ldm [d1+8], d0", load.to_string());
+        let store = Instruction::StoreMemIndexed { src_reg: 0, base: 1, offset: -8, width: Width::B4 };
+        assert_eq!("strm.w d0, [d1-8]", store.to_string());
+        assert_eq!("halt", Instruction::Halt.to_string());
+    }
+
+    #[test]
+    fn printed_instructions_assemble_back_to_the_same_words() {
+        let source = "top:\nload $230, d1\nadd d0, d1, d3\nsub d3, d2, d1\nmult d1, d1, d0\ncmp d2, d3\n\
+                      jlt d2\ninc d1\ndec ip\nldm @12, d3\nstrm d3, @12\npush sp\npop d1\ncall d2\nret\nand d0, d1, d2\nor d1, d2, d3\nxor d2, d3, d0\nnot d3, d1\n\
+                      shl d0, d1, d2\nshr d1, d2, d3\nsar d2, d3, d0\n\
+                      jge d0\njle d1\nja d2\njb d3\njo sp\njs d1\ncopy flags, d0\n\
+                      addi d0, $-7, d1\nsubi d1, $7, d2\nmuli d2, $-1, d3\ncmpi d3, $12\n\
+                      ldm [d1], d0\nldm [sp+2], d3\nstrm d2, [d0-8]\n\
+                      ldm.b @1, d0\nldm.wu [d1+4], d2\nstrm.h d3, @2\nstrm.w d3, [sp-4]\nldm.d @8, d1\n\
+                      br $-3\nbrz $0\nbrnz $7\nbrgt $-1\nbrlt $2\nbrge $3\nbrle $4\nbra $5\nbrb $6\nbro $-7\nbrs $8\n\
+                      in $0, d2\nout d2, $65535\n\
+                      load $-9, d1\nloadh $-2, d1\ncopy d0, ptbr\ncopy epc, d1\ntret\njnz top\nhalt";
+        let program = assemble(source).unwrap();
+        let printed: Vec<String> = program.iter().map(|word| Instruction::from(*word).to_string()).collect();
+        assert_eq!(program, assemble(&printed.join("\n")).unwrap());
+    }
+
+    #[test]
+    fn listing_annotates_addresses_and_marks_illegal_words() {
+        let mut memory = Memory::new_with_size(64);
+        memory.write_instruction(0, Instruction::Load { value: 449, dest_reg: 0 }.encode().unwrap()).unwrap();
+        memory.write_instruction(1, 0b1000000000).unwrap();
+
+        let expected = "     0: load $449, d0\n     1: <illegal 0x0000000000000200>\n     2: halt\n";
+        assert_eq!(expected, disassemble(&memory, 0..3).unwrap());
+    }
+
+    #[test]
+    fn listing_past_the_end_of_memory_fails() {
+        let memory = Memory::new_with_size(16);
+        assert!(disassemble(&memory, 0..3).is_err());
+    }
+}
@@ -1,11 +1,184 @@
 use crate::runtime::Word;
+use crate::registers::Registers;
+use crate::memory::Operation;
 
-#[derive(Debug)]
+use std::fmt;
+
+/*
+ * Runtime errors carry the instr_pointer of the instruction that raised them. Memory and
+ * MMU faults can also come from the host calling Memory or Mmu directly, outside of any
+ * instruction, so their instr_pointer is None until the runtime attributes them with `at`.
+ */
+#[derive(Debug, PartialEq)]
 pub enum Error {
     IllegalOpcode { instruction: Word, instr_pointer: Word },
     InvalidRegister { number: usize, instr_pointer: Word },
+    ReadOnlyRegister { number: usize, instr_pointer: Word },
+    UnknownRegister { name: String },
     DivisionByZero { instr_pointer: Word },
-    InvalidMemoryAddress { requested_address: usize, upper_bound: usize },
+    ArithmeticOverflow { instr_pointer: Word },
+    StackOverflow { stack_pointer: Word, instr_pointer: Word },
+    StackUnderflow { stack_pointer: Word, instr_pointer: Word },
+    InvalidMemoryAddress { requested_address: usize, upper_bound: usize, instr_pointer: Option<Word> },
+    InvalidInstructionIndex { index: usize, instr_pointer: Option<Word> },
+    MisalignedAccess { address: Word, width: usize, instr_pointer: Word },
+    ReadProtected { address: usize, region: String, instr_pointer: Option<Word> },
+    WriteProtected { address: usize, region: String, instr_pointer: Option<Word> },
+    ExecuteProtected { address: usize, region: String, instr_pointer: Option<Word> },
+    PageFault { address: Word, operation: Operation, instr_pointer: Option<Word> },
+    UnmappedPort { port: u16, instr_pointer: Word },
+    DeviceFailure { port: u16, message: String, instr_pointer: Word },
+    OperandOutOfRange { line: Option<usize>, field: &'static str, value: Word, bits: u32 },
+    UnknownMnemonic { line: usize, mnemonic: String },
+    InvalidOperand { line: usize, operand: String },
+    WrongOperandCount { line: usize, expected: usize, found: usize },
+    UndefinedLabel { line: usize, label: String },
+    DuplicateLabel { line: usize, label: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /*
+     * Attributes a runtime error to the instruction at `instr_pointer`.
+     */
+    pub(crate) fn at(self, instr_pointer: Word) -> Self {
+        match self {
+            Error::IllegalOpcode { instruction, .. }         => Error::IllegalOpcode { instruction, instr_pointer },
+            Error::InvalidRegister { number, .. }            => Error::InvalidRegister { number, instr_pointer },
+            Error::ReadOnlyRegister { number, .. }           => Error::ReadOnlyRegister { number, instr_pointer },
+            Error::DivisionByZero { .. }                     => Error::DivisionByZero { instr_pointer },
+            Error::ArithmeticOverflow { .. }                 => Error::ArithmeticOverflow { instr_pointer },
+            Error::StackOverflow { stack_pointer, .. }       => Error::StackOverflow { stack_pointer, instr_pointer },
+            Error::StackUnderflow { stack_pointer, .. }      => Error::StackUnderflow { stack_pointer, instr_pointer },
+            Error::InvalidMemoryAddress { requested_address, upper_bound, .. } =>
+                Error::InvalidMemoryAddress { requested_address, upper_bound, instr_pointer: Some(instr_pointer) },
+            Error::InvalidInstructionIndex { index, .. }     => Error::InvalidInstructionIndex { index, instr_pointer: Some(instr_pointer) },
+            Error::MisalignedAccess { address, width, .. }   => Error::MisalignedAccess { address, width, instr_pointer },
+            Error::ReadProtected { address, region, .. }     => Error::ReadProtected { address, region, instr_pointer: Some(instr_pointer) },
+            Error::WriteProtected { address, region, .. }    => Error::WriteProtected { address, region, instr_pointer: Some(instr_pointer) },
+            Error::ExecuteProtected { address, region, .. }  => Error::ExecuteProtected { address, region, instr_pointer: Some(instr_pointer) },
+            Error::PageFault { address, operation, .. }      => Error::PageFault { address, operation, instr_pointer: Some(instr_pointer) },
+            Error::UnmappedPort { port, .. }                 => Error::UnmappedPort { port, instr_pointer },
+            Error::DeviceFailure { port, message, .. }       => Error::DeviceFailure { port, message, instr_pointer },
+            error                                            => error,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IllegalOpcode { instruction, instr_pointer } =>
+                write!(f, "illegal instruction {:#018x} at address {}", instruction, instr_pointer),
+            Error::InvalidRegister { number, instr_pointer } =>
+                write!(f, "register {} does not exist (used at address {})", Registers::name_of(*number), instr_pointer),
+            Error::ReadOnlyRegister { number, instr_pointer } =>
+                write!(f, "register {} is read-only (written at address {})", Registers::name_of(*number), instr_pointer),
+            Error::UnknownRegister { name } =>
+                write!(f, "unknown register name '{}'", name),
+            Error::DivisionByZero { instr_pointer } =>
+                write!(f, "division by zero at address {}", instr_pointer),
+            Error::ArithmeticOverflow { instr_pointer } =>
+                write!(f, "arithmetic overflow at address {}", instr_pointer),
+            Error::StackOverflow { stack_pointer, instr_pointer } =>
+                write!(f, "stack overflow (sp = {}) at address {}", stack_pointer, instr_pointer),
+            Error::StackUnderflow { stack_pointer, instr_pointer } =>
+                write!(f, "stack underflow (sp = {}) at address {}", stack_pointer, instr_pointer),
+            Error::InvalidMemoryAddress { requested_address, upper_bound, instr_pointer } => {
+                write!(f, "memory address {} is out of bounds (memory has {} bytes)", requested_address, upper_bound)?;
+                attribute(f, *instr_pointer, " at address ", "")
+            },
+            Error::InvalidInstructionIndex { index, instr_pointer } => {
+                write!(f, "instruction index {} is beyond any memory address", index)?;
+                attribute(f, *instr_pointer, " (fetched at address ", ")")
+            },
+            Error::MisalignedAccess { address, width, instr_pointer } =>
+                write!(f, "misaligned {}-byte access to address {} at address {}", width, address, instr_pointer),
+            Error::ReadProtected { address, region, instr_pointer } => {
+                write!(f, "memory address {} in region '{}' is not readable", address, region)?;
+                attribute(f, *instr_pointer, " (read at address ", ")")
+            },
+            Error::WriteProtected { address, region, instr_pointer } => {
+                write!(f, "memory address {} in region '{}' is not writable", address, region)?;
+                attribute(f, *instr_pointer, " (written at address ", ")")
+            },
+            Error::ExecuteProtected { address, region, instr_pointer } => {
+                write!(f, "memory address {} in region '{}' is not executable", address, region)?;
+                attribute(f, *instr_pointer, " (fetched at address ", ")")
+            },
+            Error::PageFault { address, operation, instr_pointer } => {
+                let access = match operation {
+                    Operation::Read    => "load from",
+                    Operation::Write   => "store to",
+                    Operation::Execute => "fetch from",
+                };
+                write!(f, "page fault on {} virtual address {:#x}", access, address)?;
+                attribute(f, *instr_pointer, " at address ", "")
+            },
+            Error::UnmappedPort { port, instr_pointer } =>
+                write!(f, "no device on port {} (used at address {})", port, instr_pointer),
+            Error::DeviceFailure { port, message, instr_pointer } =>
+                write!(f, "device on port {} failed at address {}: {}", port, instr_pointer, message),
+            Error::OperandOutOfRange { line, field, value, bits } => {
+                if let Some(line) = line {
+                    write!(f, "line {}: ", line)?;
+                }
+                write!(f, "operand {} = {} does not fit in {} bits", field, value, bits)
+            },
+            Error::UnknownMnemonic { line, mnemonic } =>
+                write!(f, "line {}: unknown mnemonic '{}'", line, mnemonic),
+            Error::InvalidOperand { line, operand } =>
+                write!(f, "line {}: invalid operand '{}'", line, operand),
+            Error::WrongOperandCount { line, expected, found } =>
+                write!(f, "line {}: expected {} operands, found {}", line, expected, found),
+            Error::UndefinedLabel { line, label } =>
+                write!(f, "line {}: undefined label '{}'", line, label),
+            Error::DuplicateLabel { line, label } =>
+                write!(f, "line {}: label '{}' is already defined", line, label),
+        }
+    }
+}
+
+/*
+ * Writes where a fault happened, if it is known.
+ */
+fn attribute(f: &mut fmt::Formatter, instr_pointer: Option<Word>, before: &str, after: &str) -> fmt::Result {
+    match instr_pointer {
+        Some(instr_pointer) => write!(f, "{}{}{}", before, instr_pointer, after),
+        None => Ok(()),
+    }
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
\ No newline at end of file
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_describe_themselves() {
+        let error = Error::DivisionByZero { instr_pointer: 12 };
+        assert_eq!("division by zero at address 12", error.to_string());
+
+        let error = Error::IllegalOpcode { instruction: 0b1000000000, instr_pointer: 3 };
+        assert_eq!("illegal instruction 0x0000000000000200 at address 3", error.to_string());
+
+        let error = Error::InvalidMemoryAddress { requested_address: 100, upper_bound: 64, instr_pointer: Some(3) };
+        assert_eq!("memory address 100 is out of bounds (memory has 64 bytes) at address 3", error.to_string());
+        let error = Error::InvalidMemoryAddress { requested_address: 100, upper_bound: 64, instr_pointer: None };
+        assert_eq!("memory address 100 is out of bounds (memory has 64 bytes)", error.to_string());
+
+        let error = Error::InvalidRegister { number: 19, instr_pointer: 1 };
+        assert_eq!("register d19 does not exist (used at address 1)", error.to_string());
+
+        let error = Error::WriteProtected { address: 8, region: "code".to_string(), instr_pointer: Some(2) };
+        assert_eq!("memory address 8 in region 'code' is not writable (written at address 2)", error.to_string());
+
+        let error = Error::PageFault { address: 0x2008, operation: Operation::Read, instr_pointer: Some(8) };
+        assert_eq!("page fault on load from virtual address 0x2008 at address 8", error.to_string());
+
+        let error: Box<dyn std::error::Error> = Box::new(Error::UndefinedLabel { line: 4, label: "loop".to_string() });
+        assert_eq!("line 4: undefined label 'loop'", error.to_string());
+    }
+}
\ No newline at end of file
@@ -1,14 +1,15 @@
 use crate::runtime::Word;
+use crate::error::{ Error, Result };
 
-use std::convert::From;
+use std::convert::{ From, TryFrom };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Instruction {
     Illegal,
     Halt,
     Load { value: Word, dest_reg: u8 },
-    LoadMem { src_addr: Word, dest_reg: u8 },
-    StoreMem { src_reg: u8, dest_addr: Word },
+    LoadMem { src_addr: Word, dest_reg: u8, width: Width, unsigned: bool },
+    StoreMem { src_reg: u8, dest_addr: Word, width: Width },
     Copy { src: u8, dest: u8 },
     Add { src1: u8, src2: u8, dest: u8 },
     Sub { src1: u8, src2: u8, dest: u8 },
@@ -22,18 +23,119 @@ pub enum Instruction {
     Jlt { src: u8 },
     Inc { dest: u8 },
     Dec { dest: u8 },
+    Push { src: u8 },
+    Pop { dest: u8 },
+    Call { src: u8 },
+    Ret,
+    And { src1: u8, src2: u8, dest: u8 },
+    Or { src1: u8, src2: u8, dest: u8 },
+    Xor { src1: u8, src2: u8, dest: u8 },
+    Not { src: u8, dest: u8 },
+    Shl { src1: u8, src2: u8, dest: u8 },
+    Shr { src1: u8, src2: u8, dest: u8 },
+    Sar { src1: u8, src2: u8, dest: u8 },
+    Jge { src: u8 },
+    Jle { src: u8 },
+    Ja { src: u8 },
+    Jb { src: u8 },
+    Jo { src: u8 },
+    Js { src: u8 },
+    AddI { src: u8, imm: Word, dest: u8 },
+    SubI { src: u8, imm: Word, dest: u8 },
+    MultI { src: u8, imm: Word, dest: u8 },
+    CmpI { src: u8, imm: Word },
+    LoadMemIndexed { base: u8, offset: Word, dest_reg: u8, width: Width, unsigned: bool },
+    StoreMemIndexed { src_reg: u8, base: u8, offset: Word, width: Width },
+    Br { offset: Word },
+    Brz { offset: Word },
+    Brnz { offset: Word },
+    Brgt { offset: Word },
+    Brlt { offset: Word },
+    Brge { offset: Word },
+    Brle { offset: Word },
+    Bra { offset: Word },
+    Brb { offset: Word },
+    Bro { offset: Word },
+    Brs { offset: Word },
+    In { port: u16, dest: u8 },
+    Out { src: u8, port: u16 },
+    LoadHigh { value: Word, dest_reg: u8 },
+    TrapReturn,
+}
+
+/*
+ * How many bytes a memory instruction transfers, named by that number so B8 is a whole
+ * Word. Loads narrower than a word are sign-extended unless they are marked unsigned, in
+ * which case they are zero-extended.
+ */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Width {
+    B8,
+    B1,
+    B2,
+    B4,
+}
+
+impl Width {
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::B1 => 1,
+            Width::B2 => 2,
+            Width::B4 => 4,
+            Width::B8 => 8,
+        }
+    }
+
+    /*
+     * Suffix used by the assembler: "ldm.b", "strm.h", ...
+     */
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Width::B1 => "b",
+            Width::B2 => "h",
+            Width::B4 => "w",
+            Width::B8 => "d",
+        }
+    }
+
+    pub fn from_suffix(suffix: &str) -> Option<Self> {
+        [Width::B1, Width::B2, Width::B4, Width::B8].iter().copied().find(|width| width.suffix() == suffix)
+    }
+
+    fn code(self) -> Word {
+        match self {
+            Width::B8 => 0,
+            Width::B1 => 1,
+            Width::B2 => 2,
+            Width::B4 => 3,
+        }
+    }
+
+    fn from_code(code: Word) -> Self {
+        match code & 0b11 {
+            0 => Width::B8,
+            1 => Width::B1,
+            2 => Width::B2,
+            _ => Width::B4,
+        }
+    }
 }
 
 /*
  * For each instruction there is a corresponding parsing function to be used on the
  * implementation for the "From" trait. Each function has a comment describing the
  * binary layout of the instruction.
+ *
+ * Register operands are indexes into the register file (see `Registers`). Every register
+ * field is at least 8 bits wide, which covers all data and special registers; only its
+ * low 8 bits are read.
  */
 impl Instruction {
     const OPCODE_OFFSET: usize = 10;
     const OPCODE_MASK: Word = 0b000000_1111111111;
 
-    const LOAD_RANDS_MASK: Word = 0b00000000_1111111111111111111111111111111111111111111111;
+    const LOAD_VALUE_WIDTH: u32 = 46;
+    const LOAD_HIGH_VALUE_WIDTH: u32 = 18;
     const LOAD_DEST_OFFSET: usize = 46;
 
     const COPY_RAND2_OFFSET: usize = 27;
@@ -53,22 +155,66 @@ impl Instruction {
 
     const CMP_RAND2_OFFSET: usize = 27;
 
+    const BITWISE_RAND2_OFFSET: usize = 18;
+    const BITWISE_DEST_OFFSET: usize = 36;
+
+    const NOT_DEST_OFFSET: usize = 27;
+
+    const IMM_DEST_OFFSET: usize = 8;
+    const IMM_VALUE_OFFSET: usize = 16;
+
+    const INDEXED_BASE_OFFSET: usize = 8;
+    const INDEXED_WIDTH_OFFSET: usize = 16;
+    const INDEXED_UNSIGNED_OFFSET: usize = 18;
+    const INDEXED_DISP_OFFSET: usize = 19;
+    const INDEXED_DISP_WIDTH: u32 = 35;
+
+    const PORT_OFFSET: usize = 8;
+
+    const LOAD_MEM_SRC_MASK: Word = 0b000000000000000000000000000_111111111111111111111111111;
     const LOAD_MEM_DEST_OFFSET: usize = 27;
+    const LOAD_MEM_WIDTH_OFFSET: usize = 35;
+    const LOAD_MEM_UNSIGNED_OFFSET: usize = 37;
+    const STORE_MEM_DEST_MASK: Word = 0b000000000000000000000000000_111111111111111111111111111;
     const STORE_MEM_DEST_OFFSET: usize = 27;
+    const STORE_MEM_WIDTH_OFFSET: usize = 8;
+
+    fn sign_extend(value: Word, bits: u32) -> Word {
+        let unused = Word::BITS - bits;
+        (value << unused) >> unused
+    }
 
     /*
      * LOAD
      *
+     * VALUE is a two's complement number, sign-extended into the destination.
+     *
      *    DEST                        VALUE                         OPCODE
      * 0b00000000_0000000000000000000000000000000000000000000000(_0000000000)
      * 0x00_00_00_00_00_00_00_00
      */
     fn parse_load(operands: Word) -> Self {
-        let value = (operands & Self::LOAD_RANDS_MASK) as Word;
+        let value = Self::sign_extend(operands, Self::LOAD_VALUE_WIDTH);
         let dest_reg = (operands >> Self::LOAD_DEST_OFFSET) as u8;
         Instruction::Load { value, dest_reg }
     }
 
+    /*
+     * LOADH
+     *
+     * Replaces the upper 18 bits of DEST with VALUE, keeping the lower 46 bits, so a LOAD
+     * followed by a LOADH can build any word. VALUE is a two's complement number, which
+     * makes it the word shifted right arithmetically by 46.
+     *
+     *    DEST                    (unused)                 VALUE        OPCODE
+     * 0b00000000_0000000000000000000000000000_000000000000000000(_0000000000)
+     */
+    fn parse_load_high(operands: Word) -> Self {
+        let value = Self::sign_extend(operands, Self::LOAD_HIGH_VALUE_WIDTH);
+        let dest_reg = (operands >> Self::LOAD_DEST_OFFSET) as u8;
+        Instruction::LoadHigh { value, dest_reg }
+    }
+
     /*
      * COPY
      *
@@ -219,25 +365,371 @@ impl Instruction {
     /*
      * LDM
      *
-     *             DEST                           SRC               OPCODE
-     * 0b000000000000000000000000000_000000000000000000000000000(_0000000000)
+     * WIDTH is 0 for a whole word, 1 for a byte, 2 for a half word and 3 for a 32-bit word.
+     * U selects zero instead of sign extension.
+     *
+     *      (unused)    U WIDTH DEST                 SRC                OPCODE
+     * 0b0000000000000000_0_00_00000000_000000000000000000000000000(_0000000000)
      */
     fn parse_load_mem(operands: Word) -> Self {
-        let src_addr = (operands as i16) as Word; // TODO: use bit mask to extract src_addr
+        let src_addr = operands & Self::LOAD_MEM_SRC_MASK;
         let dest_reg = (operands >> Self::LOAD_MEM_DEST_OFFSET) as u8;
-        Instruction::LoadMem { src_addr, dest_reg }
+        let width = Width::from_code(operands >> Self::LOAD_MEM_WIDTH_OFFSET);
+        let unsigned = (operands >> Self::LOAD_MEM_UNSIGNED_OFFSET) & 1 == 1;
+        Instruction::LoadMem { src_addr, dest_reg, width, unsigned }
     }
 
     /*
      * STRM
      *
-     *             DEST                           SRC               OPCODE
-     * 0b000000000000000000000000000_000000000000000000000000000(_0000000000)
+     * WIDTH as for LDM.
+     *
+     *             DEST                   (unused)     WIDTH  SRC       OPCODE
+     * 0b000000000000000000000000000_00000000000000000_00_00000000(_0000000000)
      */
     fn parse_store_mem(operands: Word) -> Self {
         let src_reg = operands as u8;
-        let dest_addr = (operands >> Self::STORE_MEM_DEST_OFFSET) as Word;
-        Instruction::StoreMem { src_reg, dest_addr }
+        let width = Width::from_code(operands >> Self::STORE_MEM_WIDTH_OFFSET);
+        let dest_addr = (operands >> Self::STORE_MEM_DEST_OFFSET) & Self::STORE_MEM_DEST_MASK;
+        Instruction::StoreMem { src_reg, dest_addr, width }
+    }
+
+    /*
+     * PUSH
+     *
+     *                            SRC                               OPCODE
+     * 0b000000000000000000000000000000000000000000000000000000(_0000000000)
+     */
+    fn parse_push(operands: Word) -> Self {
+        Instruction::Push { src: operands as u8 }
+    }
+
+    /*
+     * POP
+     *
+     *                           DEST                               OPCODE
+     * 0b000000000000000000000000000000000000000000000000000000(_0000000000)
+     */
+    fn parse_pop(operands: Word) -> Self {
+        Instruction::Pop { dest: operands as u8 }
+    }
+
+    /*
+     * CALL
+     *
+     *                            SRC                               OPCODE
+     * 0b000000000000000000000000000000000000000000000000000000(_0000000000)
+     */
+    fn parse_call(operands: Word) -> Self {
+        Instruction::Call { src: operands as u8 }
+    }
+
+    /*
+     * AND, OR, XOR, SHL, SHR, SAR
+     *
+     *          DEST               SRC2               SRC1           OPCODE
+     * 0b000000000000000000_000000000000000000_000000000000000000(_0000000000)
+     */
+    fn parse_bitwise(opcode: Word, operands: Word) -> Self {
+        let src1 = operands as u8;
+        let src2 = (operands >> Self::BITWISE_RAND2_OFFSET) as u8;
+        let dest = (operands >> Self::BITWISE_DEST_OFFSET) as u8;
+        match opcode {
+            21 => Instruction::And { src1, src2, dest },
+            22 => Instruction::Or { src1, src2, dest },
+            23 => Instruction::Xor { src1, src2, dest },
+            25 => Instruction::Shl { src1, src2, dest },
+            26 => Instruction::Shr { src1, src2, dest },
+            _  => Instruction::Sar { src1, src2, dest },
+        }
+    }
+
+    /*
+     * NOT
+     *
+     *             DEST                          SRC                OPCODE
+     * 0b000000000000000000000000000_000000000000000000000000000(_0000000000)
+     */
+    fn parse_not(operands: Word) -> Self {
+        let src = operands as u8;
+        let dest = (operands >> Self::NOT_DEST_OFFSET) as u8;
+        Instruction::Not { src, dest }
+    }
+
+    /*
+     * JGE, JLE, JA, JB, JO, JS
+     *
+     *                            SRC                               OPCODE
+     * 0b000000000000000000000000000000000000000000000000000000(_0000000000)
+     */
+    fn parse_cond_jump(opcode: Word, operands: Word) -> Self {
+        let src = operands as u8;
+        match opcode {
+            28 => Instruction::Jge { src },
+            29 => Instruction::Jle { src },
+            30 => Instruction::Ja { src },
+            31 => Instruction::Jb { src },
+            32 => Instruction::Jo { src },
+            _  => Instruction::Js { src },
+        }
+    }
+
+    /*
+     * ADDI, SUBI, MULI, CMPI
+     *
+     * The immediate is a two's complement value, sign-extended by the arithmetic shift that
+     * separates it from the rest of the word. CMPI leaves DEST zeroed.
+     *
+     *                  IMM                     DEST     SRC      OPCODE
+     * 0b00000000000000000000000000000000000000_00000000_00000000(_0000000000)
+     */
+    fn parse_immediate(opcode: Word, operands: Word) -> Self {
+        let src = operands as u8;
+        let dest = (operands >> Self::IMM_DEST_OFFSET) as u8;
+        let imm = operands >> Self::IMM_VALUE_OFFSET;
+        match opcode {
+            34 => Instruction::AddI { src, imm, dest },
+            35 => Instruction::SubI { src, imm, dest },
+            36 => Instruction::MultI { src, imm, dest },
+            _  => Instruction::CmpI { src, imm },
+        }
+    }
+
+    /*
+     * LDMX, STRMX
+     *
+     * The effective address is the value of BASE plus the sign-extended OFFSET. REG is the
+     * destination of a load and the source of a store. WIDTH and U are as for LDM; stores
+     * ignore U.
+     *
+     *                OFFSET               U WIDTH BASE     REG      OPCODE
+     * 0b00000000000000000000000000000000000_0_00_00000000_00000000(_0000000000)
+     */
+    fn parse_indexed(opcode: Word, operands: Word) -> Self {
+        let reg = operands as u8;
+        let base = (operands >> Self::INDEXED_BASE_OFFSET) as u8;
+        let width = Width::from_code(operands >> Self::INDEXED_WIDTH_OFFSET);
+        let offset = operands >> Self::INDEXED_DISP_OFFSET;
+        match opcode {
+            38 => {
+                let unsigned = (operands >> Self::INDEXED_UNSIGNED_OFFSET) & 1 == 1;
+                Instruction::LoadMemIndexed { base, offset, dest_reg: reg, width, unsigned }
+            },
+            _  => Instruction::StoreMemIndexed { src_reg: reg, base, offset, width },
+        }
+    }
+
+    /*
+     * BR, BRZ, BRNZ, BRGT, BRLT, BRGE, BRLE, BRA, BRB, BRO, BRS
+     *
+     * The offset is sign-extended and counted from the address of the branch itself.
+     *
+     *                          OFFSET                              OPCODE
+     * 0b000000000000000000000000000000000000000000000000000000(_0000000000)
+     */
+    fn parse_branch(opcode: Word, offset: Word) -> Self {
+        match opcode {
+            40 => Instruction::Br { offset },
+            41 => Instruction::Brz { offset },
+            42 => Instruction::Brnz { offset },
+            43 => Instruction::Brgt { offset },
+            44 => Instruction::Brlt { offset },
+            45 => Instruction::Brge { offset },
+            46 => Instruction::Brle { offset },
+            47 => Instruction::Bra { offset },
+            48 => Instruction::Brb { offset },
+            49 => Instruction::Bro { offset },
+            _  => Instruction::Brs { offset },
+        }
+    }
+
+    /*
+     * IN, OUT
+     *
+     * REG is the destination of IN and the source of OUT.
+     *
+     *                         PORT              REG      OPCODE
+     * 0b000000000000000000000000000000_0000000000000000_00000000(_0000000000)
+     */
+    fn parse_port(opcode: Word, operands: Word) -> Self {
+        let reg = operands as u8;
+        let port = (operands >> Self::PORT_OFFSET) as u16;
+        match opcode {
+            51 => Instruction::In { port, dest: reg },
+            _  => Instruction::Out { src: reg, port },
+        }
+    }
+}
+
+/*
+ * Encoding is the exact inverse of decoding: each operand is placed in the same field its
+ * parsing function reads it from, and operands that do not fit their field are rejected.
+ */
+impl Instruction {
+    const ILLEGAL_OPCODE: Word = Self::OPCODE_MASK;
+
+    const LOAD_DEST_BITS: u32 = 8;
+    const COPY_RAND_BITS: u32 = 27;
+    const ARITH_RAND_BITS: u32 = 18;
+    const DIV_RAND_BITS: u32 = 13;
+    const DIV_REM_BITS: u32 = 15;
+    const CMP_RAND_BITS: u32 = 27;
+    const SINGLE_RAND_BITS: u32 = 54;
+    const MEM_RAND_BITS: u32 = 27;
+    const IMM_REG_BITS: u32 = 8;
+    const IMM_VALUE_BITS: u32 = 38;
+    const PORT_BITS: u32 = 16;
+
+    fn pack(opcode: Word, operands: Word) -> Word {
+        (operands << Self::OPCODE_OFFSET) | opcode
+    }
+
+    fn field(name: &'static str, value: Word, bits: u32, offset: usize) -> Result<Word> {
+        if value < 0 || value >= 1 << bits {
+            Err(Error::OperandOutOfRange { line: None, field: name, value, bits })
+        } else {
+            Ok(value << offset)
+        }
+    }
+
+    fn signed_field(name: &'static str, value: Word, bits: u32, offset: usize) -> Result<Word> {
+        let bound = 1 << (bits - 1);
+        if value < -bound || value >= bound {
+            Err(Error::OperandOutOfRange { line: None, field: name, value, bits })
+        } else {
+            Ok((value & ((1 << bits) - 1)) << offset)
+        }
+    }
+
+    fn reg(name: &'static str, reg: u8, bits: u32, offset: usize) -> Result<Word> {
+        Self::field(name, reg as Word, bits, offset)
+    }
+
+    fn immediate(src: u8, imm: Word, dest: u8) -> Result<Word> {
+        Ok(Self::reg("src", src, Self::IMM_REG_BITS, 0)?
+            | Self::reg("dest", dest, Self::IMM_REG_BITS, Self::IMM_DEST_OFFSET)?
+            | Self::signed_field("imm", imm, Self::IMM_VALUE_BITS, Self::IMM_VALUE_OFFSET)?)
+    }
+
+    fn bitwise(src1: u8, src2: u8, dest: u8) -> Result<Word> {
+        Ok(Self::reg("src1", src1, Self::ARITH_RAND_BITS, 0)?
+            | Self::reg("src2", src2, Self::ARITH_RAND_BITS, Self::BITWISE_RAND2_OFFSET)?
+            | Self::reg("dest", dest, Self::ARITH_RAND_BITS, Self::BITWISE_DEST_OFFSET)?)
+    }
+
+    fn indexed(reg_name: &'static str, reg: u8, base: u8, offset: Word, width: Width, unsigned: bool) -> Result<Word> {
+        Ok(Self::reg(reg_name, reg, Self::IMM_REG_BITS, 0)?
+            | Self::reg("base", base, Self::IMM_REG_BITS, Self::INDEXED_BASE_OFFSET)?
+            | width.code() << Self::INDEXED_WIDTH_OFFSET
+            | (unsigned as Word) << Self::INDEXED_UNSIGNED_OFFSET
+            | Self::signed_field("offset", offset, Self::INDEXED_DISP_WIDTH, Self::INDEXED_DISP_OFFSET)?)
+    }
+
+    fn port(reg_name: &'static str, reg: u8, port: u16) -> Result<Word> {
+        Ok(Self::reg(reg_name, reg, Self::IMM_REG_BITS, 0)?
+            | Self::field("port", port as Word, Self::PORT_BITS, Self::PORT_OFFSET)?)
+    }
+
+    pub fn encode(&self) -> Result<Word> {
+        let (opcode, operands) = match *self {
+            Instruction::Illegal => return Ok(Self::ILLEGAL_OPCODE),
+            Instruction::Halt => (0, 0),
+            Instruction::Load { value, dest_reg } => (1,
+                Self::signed_field("value", value, Self::LOAD_VALUE_WIDTH, 0)?
+                | Self::reg("dest_reg", dest_reg, Self::LOAD_DEST_BITS, Self::LOAD_DEST_OFFSET)?),
+            Instruction::Add { src1, src2, dest } => (2,
+                Self::reg("src1", src1, Self::ARITH_RAND_BITS, 0)?
+                | Self::reg("src2", src2, Self::ARITH_RAND_BITS, Self::ADD_RAND2_OFFSET)?
+                | Self::reg("dest", dest, Self::ARITH_RAND_BITS, Self::ADD_DEST_OFFSET)?),
+            Instruction::Sub { src1, src2, dest } => (3,
+                Self::reg("src1", src1, Self::ARITH_RAND_BITS, 0)?
+                | Self::reg("src2", src2, Self::ARITH_RAND_BITS, Self::SUB_RAND2_OFFSET)?
+                | Self::reg("dest", dest, Self::ARITH_RAND_BITS, Self::SUB_DEST_OFFSET)?),
+            Instruction::Mult { src1, src2, dest } => (4,
+                Self::reg("src1", src1, Self::ARITH_RAND_BITS, 0)?
+                | Self::reg("src2", src2, Self::ARITH_RAND_BITS, Self::MULT_RAND2_OFFSET)?
+                | Self::reg("dest", dest, Self::ARITH_RAND_BITS, Self::MULT_DEST_OFFSET)?),
+            Instruction::Cmp { src1, src2 } => (5,
+                Self::reg("src1", src1, Self::CMP_RAND_BITS, 0)?
+                | Self::reg("src2", src2, Self::CMP_RAND_BITS, Self::CMP_RAND2_OFFSET)?),
+            Instruction::Jmp { src } => (6, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Jz { src }  => (7, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Jnz { src } => (8, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Jgt { src } => (9, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Jlt { src } => (10, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Div { src1, src2, quot_dest, rem_dest } => (11,
+                Self::reg("src1", src1, Self::DIV_RAND_BITS, 0)?
+                | Self::reg("src2", src2, Self::DIV_RAND_BITS, Self::DIV_RAND2_OFFSET)?
+                | Self::reg("quot_dest", quot_dest, Self::DIV_RAND_BITS, Self::DIV_QUOT_OFFSET)?
+                | Self::reg("rem_dest", rem_dest, Self::DIV_REM_BITS, Self::DIV_REM_OFFSET)?),
+            Instruction::Copy { src, dest } => (12,
+                Self::reg("src", src, Self::COPY_RAND_BITS, 0)?
+                | Self::reg("dest", dest, Self::COPY_RAND_BITS, Self::COPY_RAND2_OFFSET)?),
+            Instruction::Inc { dest } => (13, Self::reg("dest", dest, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Dec { dest } => (14, Self::reg("dest", dest, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::LoadMem { src_addr, dest_reg, width, unsigned } => (15,
+                Self::field("src_addr", src_addr, Self::MEM_RAND_BITS, 0)?
+                | Self::reg("dest_reg", dest_reg, Self::IMM_REG_BITS, Self::LOAD_MEM_DEST_OFFSET)?
+                | width.code() << Self::LOAD_MEM_WIDTH_OFFSET
+                | (unsigned as Word) << Self::LOAD_MEM_UNSIGNED_OFFSET),
+            Instruction::StoreMem { src_reg, dest_addr, width } => (16,
+                Self::reg("src_reg", src_reg, Self::IMM_REG_BITS, 0)?
+                | width.code() << Self::STORE_MEM_WIDTH_OFFSET
+                | Self::field("dest_addr", dest_addr, Self::MEM_RAND_BITS, Self::STORE_MEM_DEST_OFFSET)?),
+            Instruction::Push { src } => (17, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Pop { dest } => (18, Self::reg("dest", dest, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Call { src } => (19, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Ret => (20, 0),
+            Instruction::And { src1, src2, dest } => (21, Self::bitwise(src1, src2, dest)?),
+            Instruction::Or { src1, src2, dest }  => (22, Self::bitwise(src1, src2, dest)?),
+            Instruction::Xor { src1, src2, dest } => (23, Self::bitwise(src1, src2, dest)?),
+            Instruction::Not { src, dest } => (24,
+                Self::reg("src", src, Self::COPY_RAND_BITS, 0)?
+                | Self::reg("dest", dest, Self::COPY_RAND_BITS, Self::NOT_DEST_OFFSET)?),
+            Instruction::Shl { src1, src2, dest } => (25, Self::bitwise(src1, src2, dest)?),
+            Instruction::Shr { src1, src2, dest } => (26, Self::bitwise(src1, src2, dest)?),
+            Instruction::Sar { src1, src2, dest } => (27, Self::bitwise(src1, src2, dest)?),
+            Instruction::Jge { src } => (28, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Jle { src } => (29, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Ja { src }  => (30, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Jb { src }  => (31, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Jo { src }  => (32, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Js { src }  => (33, Self::reg("src", src, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::AddI { src, imm, dest }  => (34, Self::immediate(src, imm, dest)?),
+            Instruction::SubI { src, imm, dest }  => (35, Self::immediate(src, imm, dest)?),
+            Instruction::MultI { src, imm, dest } => (36, Self::immediate(src, imm, dest)?),
+            Instruction::CmpI { src, imm }        => (37, Self::immediate(src, imm, 0)?),
+            Instruction::LoadMemIndexed { base, offset, dest_reg, width, unsigned } => (38,
+                Self::indexed("dest_reg", dest_reg, base, offset, width, unsigned)?),
+            Instruction::StoreMemIndexed { src_reg, base, offset, width } => (39,
+                Self::indexed("src_reg", src_reg, base, offset, width, false)?),
+            Instruction::Br { offset }   => (40, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Brz { offset }  => (41, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Brnz { offset } => (42, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Brgt { offset } => (43, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Brlt { offset } => (44, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Brge { offset } => (45, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Brle { offset } => (46, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Bra { offset }  => (47, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Brb { offset }  => (48, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Bro { offset }  => (49, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::Brs { offset }  => (50, Self::signed_field("offset", offset, Self::SINGLE_RAND_BITS, 0)?),
+            Instruction::In { port, dest } => (51, Self::port("dest", dest, port)?),
+            Instruction::Out { src, port } => (52, Self::port("src", src, port)?),
+            Instruction::LoadHigh { value, dest_reg } => (53,
+                Self::signed_field("value", value, Self::LOAD_HIGH_VALUE_WIDTH, 0)?
+                | Self::reg("dest_reg", dest_reg, Self::LOAD_DEST_BITS, Self::LOAD_DEST_OFFSET)?),
+            Instruction::TrapReturn => (54, 0),
+        };
+        Ok(Self::pack(opcode, operands))
+    }
+}
+
+impl TryFrom<Instruction> for Word {
+    type Error = Error;
+
+    fn try_from(instruction: Instruction) -> Result<Self> {
+        instruction.encode()
     }
 }
 
@@ -263,6 +755,20 @@ impl From<Word> for Instruction {
             14            => Self::parse_dec(operands),
             15            => Self::parse_load_mem(operands),
             16            => Self::parse_store_mem(operands),
+            17            => Self::parse_push(operands),
+            18            => Self::parse_pop(operands),
+            19            => Self::parse_call(operands),
+            20            => Instruction::Ret,
+            21..=23       => Self::parse_bitwise(opcode, operands),
+            24            => Self::parse_not(operands),
+            25..=27       => Self::parse_bitwise(opcode, operands),
+            28..=33       => Self::parse_cond_jump(opcode, operands),
+            34..=37       => Self::parse_immediate(opcode, operands),
+            38..=39       => Self::parse_indexed(opcode, operands),
+            40..=50       => Self::parse_branch(opcode, operands),
+            51..=52       => Self::parse_port(opcode, operands),
+            53            => Self::parse_load_high(operands),
+            54            => Instruction::TrapReturn,
             x if x > 1024 => Instruction::Illegal, // we have only 2.pow(10) = 1024 opcode slots
             _             => Instruction::Illegal              // for still unimplemented instructions
         }
@@ -270,6 +776,7 @@ impl From<Word> for Instruction {
 }
 
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)] // binary literals are grouped by instruction field
 mod tests {
     use super::*;
 
@@ -289,5 +796,174 @@ mod tests {
         let expected = Instruction::Load { dest_reg: 10, value: 1000 };
         let actual = Instruction::from(instruction);
         assert_eq!(expected, actual);
+
+        let instruction: Word = 0b00001010_1111111111111111111111111111111111110000011000_0000000001;
+        let expected = Instruction::Load { dest_reg: 10, value: -1000 };
+        let actual = Instruction::from(instruction);
+        assert_eq!(expected, actual);
+    }
+
+    /*
+     * Small xorshift generator so the round-trip properties below can be checked over many
+     * operand combinations without pulling in a property testing crate.
+     */
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn reg(&mut self) -> u8 {
+            self.next() as u8
+        }
+
+        fn bits(&mut self, bits: u32) -> Word {
+            (self.next() & ((1 << bits) - 1)) as Word
+        }
+
+        fn flag(&mut self) -> bool {
+            self.next() & 1 == 1
+        }
+
+        fn width(&mut self) -> Width {
+            Width::from_code(self.next() as Word)
+        }
+
+        fn signed_bits(&mut self, bits: u32) -> Word {
+            self.bits(bits) - (1 << (bits - 1))
+        }
+    }
+
+    fn arbitrary(opcode: Word, rng: &mut Rng) -> Instruction {
+        match opcode {
+            0  => Instruction::Halt,
+            1  => Instruction::Load { value: rng.signed_bits(46), dest_reg: rng.reg() },
+            2  => Instruction::Add { src1: rng.reg(), src2: rng.reg(), dest: rng.reg() },
+            3  => Instruction::Sub { src1: rng.reg(), src2: rng.reg(), dest: rng.reg() },
+            4  => Instruction::Mult { src1: rng.reg(), src2: rng.reg(), dest: rng.reg() },
+            5  => Instruction::Cmp { src1: rng.reg(), src2: rng.reg() },
+            6  => Instruction::Jmp { src: rng.reg() },
+            7  => Instruction::Jz { src: rng.reg() },
+            8  => Instruction::Jnz { src: rng.reg() },
+            9  => Instruction::Jgt { src: rng.reg() },
+            10 => Instruction::Jlt { src: rng.reg() },
+            11 => Instruction::Div { src1: rng.reg(), src2: rng.reg(), quot_dest: rng.reg(), rem_dest: rng.reg() },
+            12 => Instruction::Copy { src: rng.reg(), dest: rng.reg() },
+            13 => Instruction::Inc { dest: rng.reg() },
+            14 => Instruction::Dec { dest: rng.reg() },
+            15 => Instruction::LoadMem { src_addr: rng.bits(27), dest_reg: rng.reg(), width: rng.width(), unsigned: rng.flag() },
+            16 => Instruction::StoreMem { src_reg: rng.reg(), dest_addr: rng.bits(27), width: rng.width() },
+            17 => Instruction::Push { src: rng.reg() },
+            18 => Instruction::Pop { dest: rng.reg() },
+            19 => Instruction::Call { src: rng.reg() },
+            20 => Instruction::Ret,
+            21 => Instruction::And { src1: rng.reg(), src2: rng.reg(), dest: rng.reg() },
+            22 => Instruction::Or { src1: rng.reg(), src2: rng.reg(), dest: rng.reg() },
+            23 => Instruction::Xor { src1: rng.reg(), src2: rng.reg(), dest: rng.reg() },
+            24 => Instruction::Not { src: rng.reg(), dest: rng.reg() },
+            25 => Instruction::Shl { src1: rng.reg(), src2: rng.reg(), dest: rng.reg() },
+            26 => Instruction::Shr { src1: rng.reg(), src2: rng.reg(), dest: rng.reg() },
+            27 => Instruction::Sar { src1: rng.reg(), src2: rng.reg(), dest: rng.reg() },
+            28 => Instruction::Jge { src: rng.reg() },
+            29 => Instruction::Jle { src: rng.reg() },
+            30 => Instruction::Ja { src: rng.reg() },
+            31 => Instruction::Jb { src: rng.reg() },
+            32 => Instruction::Jo { src: rng.reg() },
+            33 => Instruction::Js { src: rng.reg() },
+            34 => Instruction::AddI { src: rng.reg(), imm: rng.signed_bits(38), dest: rng.reg() },
+            35 => Instruction::SubI { src: rng.reg(), imm: rng.signed_bits(38), dest: rng.reg() },
+            36 => Instruction::MultI { src: rng.reg(), imm: rng.signed_bits(38), dest: rng.reg() },
+            37 => Instruction::CmpI { src: rng.reg(), imm: rng.signed_bits(38) },
+            38 => Instruction::LoadMemIndexed {
+                base: rng.reg(), offset: rng.signed_bits(35), dest_reg: rng.reg(), width: rng.width(), unsigned: rng.flag(),
+            },
+            39 => Instruction::StoreMemIndexed { src_reg: rng.reg(), base: rng.reg(), offset: rng.signed_bits(35), width: rng.width() },
+            40 => Instruction::Br { offset: rng.signed_bits(54) },
+            41 => Instruction::Brz { offset: rng.signed_bits(54) },
+            42 => Instruction::Brnz { offset: rng.signed_bits(54) },
+            43 => Instruction::Brgt { offset: rng.signed_bits(54) },
+            44 => Instruction::Brlt { offset: rng.signed_bits(54) },
+            45 => Instruction::Brge { offset: rng.signed_bits(54) },
+            46 => Instruction::Brle { offset: rng.signed_bits(54) },
+            47 => Instruction::Bra { offset: rng.signed_bits(54) },
+            48 => Instruction::Brb { offset: rng.signed_bits(54) },
+            49 => Instruction::Bro { offset: rng.signed_bits(54) },
+            50 => Instruction::Brs { offset: rng.signed_bits(54) },
+            51 => Instruction::In { port: rng.bits(16) as u16, dest: rng.reg() },
+            52 => Instruction::Out { src: rng.reg(), port: rng.bits(16) as u16 },
+            53 => Instruction::LoadHigh { value: rng.signed_bits(18), dest_reg: rng.reg() },
+            54 => Instruction::TrapReturn,
+            _  => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn decoding_an_encoded_instruction_gives_it_back() {
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+        for opcode in 0..=54 {
+            for _ in 0..1000 {
+                let instruction = arbitrary(opcode, &mut rng);
+                let word = instruction.encode().unwrap();
+                assert_eq!(opcode, word & Instruction::OPCODE_MASK);
+                assert_eq!(word, Instruction::from(word).encode().unwrap());
+                assert_eq!(instruction, Instruction::from(word));
+            }
+        }
+    }
+
+    #[test]
+    fn field_boundaries_survive_a_round_trip() {
+        let extremes = vec![
+            Instruction::Load { value: (1 << 45) - 1, dest_reg: 255 },
+            Instruction::Load { value: -(1 << 45), dest_reg: 255 },
+            Instruction::Load { value: -1, dest_reg: 0 },
+            Instruction::LoadHigh { value: -(1 << 17), dest_reg: 255 },
+            Instruction::LoadHigh { value: (1 << 17) - 1, dest_reg: 255 },
+            Instruction::LoadMem { src_addr: (1 << 27) - 1, dest_reg: 255, width: Width::B4, unsigned: true },
+            Instruction::StoreMem { src_reg: 255, dest_addr: (1 << 27) - 1, width: Width::B4 },
+            Instruction::Div { src1: 255, src2: 255, quot_dest: 255, rem_dest: 255 },
+            Instruction::AddI { src: 255, imm: (1 << 37) - 1, dest: 255 },
+            Instruction::SubI { src: 255, imm: -(1 << 37), dest: 255 },
+            Instruction::CmpI { src: 0, imm: -1 },
+            Instruction::LoadMemIndexed { base: 255, offset: -(1 << 34), dest_reg: 255, width: Width::B4, unsigned: true },
+            Instruction::StoreMemIndexed { src_reg: 255, base: 255, offset: (1 << 34) - 1, width: Width::B4 },
+            Instruction::Br { offset: -(1 << 53) },
+            Instruction::Brs { offset: (1 << 53) - 1 },
+            Instruction::In { port: u16::MAX, dest: 255 },
+            Instruction::Out { src: 255, port: u16::MAX },
+            Instruction::Illegal,
+        ];
+        for instruction in extremes {
+            let word = Word::try_from(instruction).unwrap();
+            assert_eq!(instruction, Instruction::from(word));
+        }
+    }
+
+    #[test]
+    fn operands_that_do_not_fit_their_field_are_rejected() {
+        let error = Instruction::Load { value: 1 << 45, dest_reg: 0 }.encode().unwrap_err();
+        assert!(matches!(error, Error::OperandOutOfRange { line: None, field: "value", value, bits: 46 } if value == 1 << 45));
+
+        let error = Instruction::Load { value: -(1 << 45) - 1, dest_reg: 0 }.encode().unwrap_err();
+        assert!(matches!(error, Error::OperandOutOfRange { field: "value", bits: 46, .. }));
+
+        let error = Instruction::LoadHigh { value: 1 << 17, dest_reg: 0 }.encode().unwrap_err();
+        assert!(matches!(error, Error::OperandOutOfRange { field: "value", bits: 18, .. }));
+
+        let error = Instruction::LoadMem { src_addr: 1 << 27, dest_reg: 0, width: Width::B8, unsigned: false }.encode().unwrap_err();
+        assert!(matches!(error, Error::OperandOutOfRange { field: "src_addr", bits: 27, .. }));
+
+        let error = Instruction::StoreMem { src_reg: 0, dest_addr: -3, width: Width::B1 }.encode().unwrap_err();
+        assert!(matches!(error, Error::OperandOutOfRange { field: "dest_addr", .. }));
+
+        let error = Instruction::AddI { src: 0, imm: 1 << 37, dest: 0 }.encode().unwrap_err();
+        assert!(matches!(error, Error::OperandOutOfRange { field: "imm", bits: 38, .. }));
+
+        let error = Instruction::CmpI { src: 0, imm: -(1 << 37) - 1 }.encode().unwrap_err();
+        assert!(matches!(error, Error::OperandOutOfRange { field: "imm", bits: 38, .. }));
     }
 }
\ No newline at end of file
@@ -1,6 +1,20 @@
 mod util;
-mod instruction;
-mod registers;
-mod error;
-mod memory;
-pub mod runtime;
\ No newline at end of file
+pub mod instruction;
+pub mod registers;
+pub mod error;
+pub mod memory;
+pub mod backend;
+pub mod runtime;
+pub mod assembler;
+pub mod disassembler;
+pub mod device;
+pub mod mmu;
+
+pub use crate::backend::{ Flat, MemoryBackend, Sparse };
+pub use crate::device::{ Capture, Console, Device };
+pub use crate::error::{ Error, Result };
+pub use crate::instruction::{ Instruction, Width };
+pub use crate::memory::{ Memory, Operation, Permissions, Region };
+pub use crate::mmu::Mmu;
+pub use crate::registers::{ Access, Flags, Registers };
+pub use crate::runtime::{ AlignmentMode, ExitReason, OverflowMode, Runtime, RuntimeBuilder, RuntimeState, Status, Word };
\ No newline at end of file
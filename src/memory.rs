@@ -1,38 +1,302 @@
 use crate::runtime::Word;
 use crate::error::{ Error, Result };
+use crate::backend::{ Flat, MemoryBackend, Sparse };
 
+use std::ops::Range;
+
+/*
+ * What a guest program may do with the bytes of a region.
+ */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const NONE: Self = Permissions { read: false, write: false, execute: false };
+    pub const READ_ONLY: Self = Permissions { read: true, write: false, execute: false };
+    pub const READ_WRITE: Self = Permissions { read: true, write: true, execute: false };
+    pub const READ_EXECUTE: Self = Permissions { read: true, write: false, execute: true };
+    pub const ALL: Self = Permissions { read: true, write: true, execute: true };
+
+    pub fn allows(&self, operation: Operation) -> bool {
+        match operation {
+            Operation::Read    => self.read,
+            Operation::Write   => self.write,
+            Operation::Execute => self.execute,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operation {
+    Read,
+    Write,
+    Execute,
+}
+
+/*
+ * Named range of byte addresses with its own permissions, such as the code, data or stack
+ * of a program.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub struct Region {
+    pub name: String,
+    pub addresses: Range<usize>,
+    pub permissions: Permissions,
+}
+
+/*
+ * Byte-addressed memory. Multi-byte values are stored little-endian. Instructions are
+ * words too, but they are addressed by index: instruction n lives at byte n * WORD_BYTES.
+ *
+ * Permissions only apply to guest accesses checked with `check`; the other methods give
+ * the host unrestricted access, which is how programs get loaded into read-only code.
+ */
 pub struct Memory {
-    buffer: Vec<Word>,
+    backend: Box<dyn MemoryBackend>,
+    size_bytes: usize,
+    stack_bytes: usize,
+    regions: Vec<Region>,
 }
 
 impl Memory {
+    pub const WORD_BYTES: usize = std::mem::size_of::<Word>();
+
     const DEFAULT_MEMORY_SIZE_BYTES: usize = 2097152;
+    const DEFAULT_STACK_SIZE_BYTES: usize = 65536;
 
+    /*
+     * Memory backed by a single buffer of `size_bytes`, allocated up front.
+     */
     pub fn new_with_size(size_bytes: usize) -> Self {
-        let mem_vec_size = size_bytes / std::mem::size_of::<Word>();
-        Memory { buffer: vec![0; mem_vec_size] }
+        Self::with_backend(Flat::new(size_bytes - size_bytes % Self::WORD_BYTES))
     }
 
-    pub fn write(&mut self, address: usize, data: Word) -> Result<()> {
-        if address as usize >= self.buffer.len() {
-            Err(Error::InvalidMemoryAddress { requested_address: address, upper_bound: self.buffer.len() })
-        } else {
-            self.buffer[address as usize] = data;
-            Ok(())
+    /*
+     * Memory of `size_bytes` whose pages are only allocated when written, for address
+     * spaces much larger than what a program actually uses.
+     */
+    pub fn sparse_with_size(size_bytes: usize) -> Self {
+        Self::with_backend(Sparse::new(size_bytes - size_bytes % Self::WORD_BYTES))
+    }
+
+    /*
+     * The stack takes the top of memory: 64 KiB by default, but never more than a quarter
+     * of the whole memory. Sizes are rounded down to whole words.
+     */
+    pub fn with_backend(backend: impl MemoryBackend + 'static) -> Self {
+        let size_bytes = backend.size() - backend.size() % Self::WORD_BYTES;
+        Memory { backend: Box::new(backend), size_bytes, stack_bytes: 0, regions: Vec::new() }
+            .with_stack_size(Self::DEFAULT_STACK_SIZE_BYTES.min(size_bytes / 4))
+    }
+
+    pub fn with_stack_size(mut self, size_bytes: usize) -> Self {
+        self.stack_bytes = (size_bytes - size_bytes % Self::WORD_BYTES).min(self.size_bytes);
+        self
+    }
+
+    /*
+     * Byte addresses reserved for the stack, which grows downwards from the end of the range.
+     */
+    pub fn stack_region(&self) -> Range<usize> {
+        self.size_bytes - self.stack_bytes..self.size_bytes
+    }
+
+    /*
+     * Declares a region. Bytes outside every region can be read, written and executed;
+     * where regions overlap, the one declared last wins.
+     */
+    pub fn with_region(mut self, name: &str, addresses: Range<usize>, permissions: Permissions) -> Self {
+        self.regions.push(Region { name: name.to_string(), addresses, permissions });
+        self
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn region_at(&self, address: usize) -> Option<&Region> {
+        self.regions.iter().rev().find(|region| region.addresses.contains(&address))
+    }
+
+    /*
+     * Checks that a guest may perform `operation` on the `width` bytes at `address`. The
+     * fault names the first denied byte.
+     */
+    pub fn check(&self, address: usize, width: usize, operation: Operation) -> Result<()> {
+        let denied = (address..address.saturating_add(width))
+            .filter_map(|byte| self.region_at(byte).map(|region| (byte, region)))
+            .find(|(_, region)| !region.permissions.allows(operation));
+        match denied {
+            None => Ok(()),
+            Some((address, region)) => {
+                let region = region.name.clone();
+                Err(match operation {
+                    Operation::Read    => Error::ReadProtected { address, region, instr_pointer: None },
+                    Operation::Write   => Error::WriteProtected { address, region, instr_pointer: None },
+                    Operation::Execute => Error::ExecuteProtected { address, region, instr_pointer: None },
+                })
+            }
         }
     }
 
-    pub fn read(&self, address: usize) -> Result<Word> {
-        if address as usize >= self.buffer.len() {
-            Err(Error::InvalidMemoryAddress { requested_address: address, upper_bound: self.buffer.len() })
-        } else {
-            Ok(self.buffer[address as usize])
+    fn bytes(&self, address: usize, width: usize) -> Result<Range<usize>> {
+        match address.checked_add(width) {
+            Some(end) if end <= self.size_bytes => Ok(address..end),
+            _ => Err(Error::InvalidMemoryAddress { requested_address: address, upper_bound: self.size_bytes, instr_pointer: None }),
         }
     }
+
+    /*
+     * Reads `width` bytes (1 to 8) at `address`, zero-extended.
+     */
+    pub fn load(&self, address: usize, width: usize) -> Result<u64> {
+        let mut bytes = [0; 8];
+        self.bytes(address, width)
+            .map(|range| self.backend.read(range.start, &mut bytes[..range.len()]))
+            .map(|()| u64::from_le_bytes(bytes))
+    }
+
+    /*
+     * Writes the low `width` bytes (1 to 8) of `value` at `address`.
+     */
+    pub fn store(&mut self, address: usize, width: usize, value: u64) -> Result<()> {
+        self.bytes(address, width)
+            .map(|range| self.backend.write(range.start, &value.to_le_bytes()[..range.len()]))
+    }
+
+    pub fn write(&mut self, address: usize, data: Word) -> Result<()> {
+        self.store(address, Self::WORD_BYTES, data as u64)
+    }
+
+    pub fn read(&self, address: usize) -> Result<Word> {
+        self.load(address, Self::WORD_BYTES).map(|value| value as Word)
+    }
+
+    /*
+     * Reads the words stored in the byte range `addresses`, one every WORD_BYTES bytes.
+     */
+    pub fn read_range(&self, addresses: Range<usize>) -> Result<Vec<Word>> {
+        addresses.step_by(Self::WORD_BYTES).map(|address| self.read(address)).collect()
+    }
+
+    pub fn read_bytes(&self, addresses: Range<usize>) -> Result<Vec<u8>> {
+        let width = addresses.end.saturating_sub(addresses.start);
+        self.bytes(addresses.start, width).map(|range| {
+            let mut bytes = vec![0; range.len()];
+            self.backend.read(range.start, &mut bytes);
+            bytes
+        })
+    }
+
+    /*
+     * Instructions are addressed by index rather than by byte. An index so large that its
+     * byte address does not fit in a usize is reported as an index.
+     */
+    pub fn instruction_address(&self, index: usize) -> Result<usize> {
+        index
+            .checked_mul(Self::WORD_BYTES)
+            .ok_or(Error::InvalidInstructionIndex { index, instr_pointer: None })
+    }
+
+    pub fn read_instruction(&self, index: usize) -> Result<Word> {
+        self.instruction_address(index).and_then(|address| self.read(address))
+    }
+
+    pub fn write_instruction(&mut self, index: usize, instruction: Word) -> Result<()> {
+        self.instruction_address(index).and_then(|address| self.write(address, instruction))
+    }
+
+    pub fn len(&self) -> usize {
+        self.size_bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size_bytes == 0
+    }
 }
 
 impl Default for Memory {
     fn default() -> Self {
         Self::new_with_size(Self::DEFAULT_MEMORY_SIZE_BYTES)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_stored_little_endian() {
+        let mut memory = Memory::new_with_size(16);
+        memory.write(0, 0x0807060504030201).unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8], memory.read_bytes(0..8).unwrap());
+        assert_eq!(0x0302, memory.load(1, 2).unwrap());
+
+        memory.store(9, 4, 0xAABBCCDD).unwrap();
+        assert_eq!(vec![0, 0xDD, 0xCC, 0xBB, 0xAA, 0], memory.read_bytes(8..14).unwrap());
+        assert_eq!(0xAABBCCDD00, memory.read(8).unwrap());
+    }
+
+    #[test]
+    fn accesses_must_fit_entirely_in_memory() {
+        let mut memory = Memory::new_with_size(16);
+        assert!(memory.load(15, 1).is_ok());
+        assert_eq!(Err(Error::InvalidMemoryAddress { requested_address: 9, upper_bound: 16, instr_pointer: None }), memory.read(9));
+        assert!(memory.store(usize::MAX, 2, 0).is_err());
+        assert!(memory.read_instruction(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn instructions_are_addressed_by_index() {
+        let mut memory = Memory::new_with_size(32);
+        memory.write_instruction(2, 42).unwrap();
+        assert_eq!(42, memory.read(16).unwrap());
+        assert_eq!(42, memory.read_instruction(2).unwrap());
+        assert!(memory.read_instruction(4).is_err());
+    }
+
+    #[test]
+    fn sparse_memory_behaves_like_flat_memory() {
+        let mut memory = Memory::sparse_with_size(1 << 40);
+        assert_eq!(1 << 40, memory.len());
+        assert_eq!((1 << 40) - 65536..1 << 40, memory.stack_region());
+
+        memory.write(4092, 0x0807060504030201).unwrap();
+        assert_eq!(0x0807060504030201, memory.read(4092).unwrap());
+        assert_eq!(0x0504, memory.load(4095, 2).unwrap());
+        assert_eq!(0, memory.read(1 << 39).unwrap());
+        assert_eq!(Err(Error::InvalidMemoryAddress { requested_address: 1 << 40, upper_bound: 1 << 40, instr_pointer: None }), memory.read(1 << 40));
+    }
+
+    #[test]
+    fn later_regions_take_precedence() {
+        let memory = Memory::new_with_size(64)
+            .with_region("data", 0..64, Permissions::READ_WRITE)
+            .with_region("code", 0..16, Permissions::READ_EXECUTE);
+
+        assert_eq!("code", memory.region_at(8).unwrap().name);
+        assert_eq!("data", memory.region_at(16).unwrap().name);
+        assert!(memory.check(0, 8, Operation::Execute).is_ok());
+        assert!(memory.check(16, 8, Operation::Write).is_ok());
+
+        let fault = Error::WriteProtected { address: 12, region: "code".to_string(), instr_pointer: None };
+        assert_eq!(Err(fault), memory.check(12, 8, Operation::Write));
+        let fault = Error::ExecuteProtected { address: 16, region: "data".to_string(), instr_pointer: None };
+        assert_eq!(Err(fault), memory.check(16, 8, Operation::Execute));
+    }
+
+    #[test]
+    fn bytes_outside_regions_are_unrestricted() {
+        let memory = Memory::new_with_size(64).with_region("secret", 32..40, Permissions::NONE);
+        assert!(memory.check(0, 32, Operation::Execute).is_ok());
+        assert!(memory.check(40, 8, Operation::Write).is_ok());
+        assert!(memory.check(usize::MAX, 8, Operation::Read).is_ok());
+
+        let fault = Error::ReadProtected { address: 32, region: "secret".to_string(), instr_pointer: None };
+        assert_eq!(Err(fault), memory.check(28, 8, Operation::Read));
+    }
+}
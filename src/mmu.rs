@@ -0,0 +1,144 @@
+use crate::runtime::Word;
+use crate::error::{ Error, Result };
+use crate::memory::{ Memory, Operation };
+
+/*
+ * Optional paged address translation between the runtime and physical memory, controlled
+ * by guest-visible special registers:
+ *
+ * - ptbr holds the physical byte address of the page table, which must be page-aligned.
+ *   Setting its bit 0 turns translation on;
+ * - tvec is the instruction index of the page-fault handler, or 0 if there is none;
+ * - epc, badaddr and cause describe the last trap: the faulting instruction, the virtual
+ *   address it touched and what it was doing (CAUSE_FETCH, CAUSE_LOAD or CAUSE_STORE).
+ *   badaddr and cause are read-only.
+ *
+ * The page table is a flat array of words, entry n mapping the virtual page starting at
+ * n * PAGE_SIZE. An entry holds the physical address of its frame in the bits above the
+ * page offset and the VALID, READABLE, WRITABLE and EXECUTABLE bits below it. Instruction
+ * fetches, loads, stores and the stack are all translated; an access to a page whose entry
+ * is invalid, lacks the permission or lies beyond the end of memory is a page fault.
+ *
+ * With a handler installed, a page fault is delivered to the guest as a trap: the trap
+ * registers are filled in and execution continues at tvec with translation suspended, so
+ * the handler sees physical memory. Tret resumes at epc with translation back on, which
+ * retries the faulting instruction. Without a handler the runtime stops with
+ * `Error::PageFault`.
+ */
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Mmu {
+    pub page_table: Word,
+    pub trap_vector: Word,
+    pub trap_pointer: Word,
+    pub bad_address: Word,
+    pub cause: Word,
+    pub in_trap: bool,
+}
+
+impl Mmu {
+    pub const PAGE_SIZE: usize = 4096;
+
+    pub const ENABLE: Word = 0b0001;
+
+    pub const VALID: Word = 0b0001;
+    pub const READABLE: Word = 0b0010;
+    pub const WRITABLE: Word = 0b0100;
+    pub const EXECUTABLE: Word = 0b1000;
+
+    pub const CAUSE_FETCH: Word = 1;
+    pub const CAUSE_LOAD: Word = 2;
+    pub const CAUSE_STORE: Word = 3;
+
+    const FRAME_MASK: Word = !(Self::PAGE_SIZE as Word - 1);
+
+    pub fn is_translating(&self) -> bool {
+        self.page_table & Self::ENABLE != 0 && !self.in_trap
+    }
+
+    pub fn has_handler(&self) -> bool {
+        self.trap_vector != 0
+    }
+
+    /*
+     * Physical address of the byte at virtual `address`, which is returned unchanged while
+     * translation is off.
+     */
+    pub fn translate(&self, memory: &Memory, address: usize, operation: Operation) -> Result<usize> {
+        if !self.is_translating() {
+            return Ok(address);
+        }
+        let fault = || Error::PageFault { address: address as Word, operation, instr_pointer: None };
+        let required = Self::VALID | match operation {
+            Operation::Read    => Self::READABLE,
+            Operation::Write   => Self::WRITABLE,
+            Operation::Execute => Self::EXECUTABLE,
+        };
+        let entry = (address / Self::PAGE_SIZE)
+            .checked_mul(Memory::WORD_BYTES)
+            .and_then(|offset| ((self.page_table & Self::FRAME_MASK) as usize).checked_add(offset))
+            .and_then(|entry_address| memory.read(entry_address).ok())
+            .filter(|entry| entry & required == required)
+            .ok_or_else(fault)?;
+        Ok((entry & Self::FRAME_MASK) as usize + address % Self::PAGE_SIZE)
+    }
+
+    pub fn cause_of(operation: Operation) -> Word {
+        match operation {
+            Operation::Execute => Self::CAUSE_FETCH,
+            Operation::Read    => Self::CAUSE_LOAD,
+            Operation::Write   => Self::CAUSE_STORE,
+        }
+    }
+
+    /*
+     * Records a page fault and suspends translation. The caller jumps to the trap vector.
+     */
+    pub fn enter_trap(&mut self, instr_pointer: Word, address: Word, operation: Operation) {
+        self.trap_pointer = instr_pointer;
+        self.bad_address = address;
+        self.cause = Self::cause_of(operation);
+        self.in_trap = true;
+    }
+
+    /*
+     * Resumes translation, returning where execution continues.
+     */
+    pub fn leave_trap(&mut self) -> Word {
+        self.in_trap = false;
+        self.trap_pointer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paged_memory() -> (Mmu, Memory) {
+        let mut memory = Memory::new_with_size(0x4000);
+        memory.write(0x1000, 0x2000 | Mmu::VALID | Mmu::READABLE).unwrap();
+        memory.write(0x1008, 0x3000 | Mmu::VALID | Mmu::READABLE | Mmu::WRITABLE).unwrap();
+        (Mmu { page_table: 0x1000 | Mmu::ENABLE, ..Mmu::default() }, memory)
+    }
+
+    #[test]
+    fn pages_map_to_their_frames() {
+        let (mmu, memory) = paged_memory();
+        assert_eq!(Ok(0x2010), mmu.translate(&memory, 0x10, Operation::Read));
+        assert_eq!(Ok(0x3fff), mmu.translate(&memory, 0x1fff, Operation::Write));
+
+        let off = Mmu { page_table: 0x1000, ..mmu };
+        assert_eq!(Ok(0x10), off.translate(&memory, 0x10, Operation::Execute));
+        let trapped = Mmu { in_trap: true, ..mmu };
+        assert_eq!(Ok(0x10), trapped.translate(&memory, 0x10, Operation::Write));
+    }
+
+    #[test]
+    fn missing_pages_and_permissions_fault() {
+        let (mmu, memory) = paged_memory();
+        let fault = |address, operation| Err(Error::PageFault { address, operation, instr_pointer: None });
+        assert_eq!(fault(0x10, Operation::Write), mmu.translate(&memory, 0x10, Operation::Write));
+        assert_eq!(fault(0x1010, Operation::Execute), mmu.translate(&memory, 0x1010, Operation::Execute));
+        assert_eq!(fault(0x2000, Operation::Read), mmu.translate(&memory, 0x2000, Operation::Read));
+        assert_eq!(fault(-8, Operation::Read), mmu.translate(&memory, -8i64 as usize, Operation::Read));
+    }
+}
@@ -1,46 +1,387 @@
 use crate::runtime::Word;
 use crate::error::{ Error, Result };
+use crate::mmu::Mmu;
 
-#[derive(Default)]
+/*
+ * Condition flags set by Add, Sub, Mult, Div, Inc, Dec and Cmp. `zero` and `sign` describe
+ * the result; `carry` is set when the operation overflowed as an unsigned one (a borrow, for
+ * subtraction) and `overflow` when it overflowed as a signed one. Mult sets both carry and
+ * overflow when the full product does not fit in a word; Div describes the quotient and only
+ * overflows for Word::MIN / -1.
+ */
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Flags {
+    pub zero: bool,
+    pub sign: bool,
+    pub carry: bool,
+    pub overflow: bool,
+}
+
+impl Flags {
+    const ZERO_BIT: Word = 0b0001;
+    const SIGN_BIT: Word = 0b0010;
+    const CARRY_BIT: Word = 0b0100;
+    const OVERFLOW_BIT: Word = 0b1000;
+
+    fn of_result(result: Word, carry: bool, overflow: bool) -> Self {
+        Flags { zero: result == 0, sign: result < 0, carry, overflow }
+    }
+
+    pub fn of_add(v1: Word, v2: Word) -> (Word, Self) {
+        let (result, overflow) = v1.overflowing_add(v2);
+        let (_, carry) = (v1 as u64).overflowing_add(v2 as u64);
+        (result, Self::of_result(result, carry, overflow))
+    }
+
+    pub fn of_sub(v1: Word, v2: Word) -> (Word, Self) {
+        let (result, overflow) = v1.overflowing_sub(v2);
+        let (_, carry) = (v1 as u64).overflowing_sub(v2 as u64);
+        (result, Self::of_result(result, carry, overflow))
+    }
+
+    pub fn of_mult(v1: Word, v2: Word) -> (Word, Self) {
+        let (result, overflow) = v1.overflowing_mul(v2);
+        (result, Self::of_result(result, overflow, overflow))
+    }
+
+    pub fn of_div(v1: Word, v2: Word) -> (Word, Self) {
+        let (result, overflow) = v1.overflowing_div(v2);
+        (result, Self::of_result(result, false, overflow))
+    }
+
+    /*
+     * Conditions after a comparison of v1 with v2, as signed (greater/less) or unsigned
+     * (above/below) numbers.
+     */
+    pub fn greater(self) -> bool {
+        !self.zero && self.sign == self.overflow
+    }
+
+    pub fn greater_or_equal(self) -> bool {
+        self.sign == self.overflow
+    }
+
+    pub fn less(self) -> bool {
+        self.sign != self.overflow
+    }
+
+    pub fn less_or_equal(self) -> bool {
+        self.zero || self.sign != self.overflow
+    }
+
+    pub fn above(self) -> bool {
+        !self.carry && !self.zero
+    }
+
+    pub fn below(self) -> bool {
+        self.carry
+    }
+
+    pub fn to_word(self) -> Word {
+        let bit = |set: bool, bit: Word| if set { bit } else { 0 };
+        bit(self.zero, Self::ZERO_BIT)
+            | bit(self.sign, Self::SIGN_BIT)
+            | bit(self.carry, Self::CARRY_BIT)
+            | bit(self.overflow, Self::OVERFLOW_BIT)
+    }
+
+    pub fn from_word(word: Word) -> Self {
+        Flags {
+            zero: word & Self::ZERO_BIT != 0,
+            sign: word & Self::SIGN_BIT != 0,
+            carry: word & Self::CARRY_BIT != 0,
+            overflow: word & Self::OVERFLOW_BIT != 0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/*
+ * The register file: a configurable number of general-purpose data registers followed, at
+ * fixed indexes above any data register, by the special registers. Register operands are
+ * 8 bits wide in the narrowest instruction layouts, so every index fits in a u8. The MMU
+ * registers only exist when the register file has an MMU.
+ */
 pub struct Registers {
-    pub data0: Word,
-    pub data1: Word,
-    pub data2: Word,
-    pub data3: Word,
+    pub data: Vec<Word>,
     pub instr_pointer: Word,
+    pub stack_pointer: Word,
+    pub flags: Flags,
+    pub scratch: Word,
+    pub mmu: Option<Mmu>,
 }
 
 impl Registers {
-    pub fn write(&mut self, index: usize, data: Word) -> Result<()> {
+    pub const DEFAULT_DATA_REGISTERS: usize = 16;
+    pub const MAX_DATA_REGISTERS: usize = 240;
+
+    pub const INSTR_POINTER: usize = 240;
+    pub const STACK_POINTER: usize = 241;
+    pub const FLAGS: usize = 242;
+    pub const PAGE_TABLE: usize = 243;
+    pub const TRAP_VECTOR: usize = 244;
+    pub const TRAP_POINTER: usize = 245;
+    pub const BAD_ADDRESS: usize = 246;
+    pub const CAUSE: usize = 247;
+    pub const SCRATCH: usize = 248;
+
+    /*
+     * Creates a register file with `count` data registers, capped at MAX_DATA_REGISTERS.
+     */
+    pub fn new_with_count(count: usize) -> Self {
+        Registers {
+            data: vec![0; count.min(Self::MAX_DATA_REGISTERS)],
+            instr_pointer: 0,
+            stack_pointer: 0,
+            flags: Flags::default(),
+            scratch: 0,
+            mmu: None,
+        }
+    }
+
+    pub fn with_mmu(mut self) -> Self {
+        self.mmu = Some(Mmu::default());
+        self
+    }
+
+    /*
+     * Data registers are named d0, d1, ..., the instruction pointer ip, the stack pointer sp
+     * and the (read-only) flags register flags. The MMU registers are ptbr, tvec, epc, badaddr
+     * and cause. The scratch register the assembler expands label jumps with is at. Names are
+     * only checked syntactically; whether a data register exists in a given register file is
+     * up to read/write.
+     */
+    pub fn index_of(name: &str) -> Option<usize> {
+        match name.to_lowercase().as_str() {
+            "ip" => Some(Self::INSTR_POINTER),
+            "sp" => Some(Self::STACK_POINTER),
+            "flags" => Some(Self::FLAGS),
+            "ptbr" => Some(Self::PAGE_TABLE),
+            "tvec" => Some(Self::TRAP_VECTOR),
+            "epc" => Some(Self::TRAP_POINTER),
+            "badaddr" => Some(Self::BAD_ADDRESS),
+            "cause" => Some(Self::CAUSE),
+            "at" => Some(Self::SCRATCH),
+            name => name
+                .strip_prefix('d')
+                .filter(|number| number.bytes().all(|digit| digit.is_ascii_digit()))
+                .and_then(|number| number.parse::<usize>().ok())
+                .filter(|number| *number < Self::MAX_DATA_REGISTERS),
+        }
+    }
+
+    pub fn name_of(index: usize) -> String {
         match index {
-            0 => {
-                self.data0 = data;
-                Ok(())
-            },
-            1 => {
-                self.data1 = data;
-                Ok(())
-            },
-            2 => {
-                self.data2 = data;
-                Ok(())
-            },
-            3 => {
-                self.data3 = data;
+            Self::INSTR_POINTER => "ip".to_string(),
+            Self::STACK_POINTER => "sp".to_string(),
+            Self::FLAGS => "flags".to_string(),
+            Self::PAGE_TABLE => "ptbr".to_string(),
+            Self::TRAP_VECTOR => "tvec".to_string(),
+            Self::TRAP_POINTER => "epc".to_string(),
+            Self::BAD_ADDRESS => "badaddr".to_string(),
+            Self::CAUSE => "cause".to_string(),
+            Self::SCRATCH => "at".to_string(),
+            number if number < Self::MAX_DATA_REGISTERS => format!("d{}", number),
+            number => format!("r{}", number),
+        }
+    }
+
+    /*
+     * The single place deciding what may be done with each register, or None if it does
+     * not exist:
+     *
+     * - data registers and sp are freely readable and writable;
+     * - ip is readable and writable, so a write to it is a computed jump. While an
+     *   instruction executes, ip already holds the address of the next instruction;
+     * - flags is read-only, it only changes as a side effect of arithmetic and compares;
+     * - the scratch register is readable and writable, for the assembler's own use;
+     * - with an MMU, ptbr, tvec and epc are writable, while badaddr and cause are only set
+     *   by page faults.
+     */
+    pub fn access(&self, index: usize) -> Option<Access> {
+        match index {
+            Self::INSTR_POINTER | Self::STACK_POINTER | Self::SCRATCH => Some(Access::ReadWrite),
+            Self::FLAGS => Some(Access::ReadOnly),
+            Self::PAGE_TABLE | Self::TRAP_VECTOR | Self::TRAP_POINTER => self.mmu.map(|_| Access::ReadWrite),
+            Self::BAD_ADDRESS | Self::CAUSE => self.mmu.map(|_| Access::ReadOnly),
+            number if number < self.data.len() => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+
+    fn mmu_register(mmu: &mut Mmu, index: usize) -> &mut Word {
+        match index {
+            Self::PAGE_TABLE => &mut mmu.page_table,
+            Self::TRAP_VECTOR => &mut mmu.trap_vector,
+            Self::TRAP_POINTER => &mut mmu.trap_pointer,
+            Self::BAD_ADDRESS => &mut mmu.bad_address,
+            _ => &mut mmu.cause,
+        }
+    }
+
+    pub fn write(&mut self, index: usize, data: Word) -> Result<()> {
+        match self.access(index) {
+            Some(Access::ReadWrite) => {
+                match index {
+                    Self::INSTR_POINTER => self.instr_pointer = data,
+                    Self::STACK_POINTER => self.stack_pointer = data,
+                    Self::SCRATCH => self.scratch = data,
+                    Self::PAGE_TABLE..=Self::CAUSE => {
+                        if let Some(mmu) = self.mmu.as_mut() {
+                            *Self::mmu_register(mmu, index) = data;
+                        }
+                    },
+                    number => self.data[number] = data,
+                }
                 Ok(())
             },
-            _ => Err(Error::InvalidRegister { number: index, instr_pointer: self.instr_pointer }),
+            Some(Access::ReadOnly) => Err(Error::ReadOnlyRegister { number: index, instr_pointer: self.instr_pointer }),
+            None => Err(Error::InvalidRegister { number: index, instr_pointer: self.instr_pointer }),
         }
     }
 
     pub fn read(&self, index: usize) -> Result<Word> {
-        match index {
-            0 => Ok(self.data0),
-            1 => Ok(self.data1),
-            2 => Ok(self.data2),
-            3 => Ok(self.data3),
-            4 => Ok(self.instr_pointer),
-            _ => Err(Error::InvalidRegister { number: index, instr_pointer: self.instr_pointer }),
+        match self.access(index) {
+            Some(_) => Ok(match index {
+                Self::INSTR_POINTER => self.instr_pointer,
+                Self::STACK_POINTER => self.stack_pointer,
+                Self::FLAGS => self.flags.to_word(),
+                Self::SCRATCH => self.scratch,
+                Self::PAGE_TABLE..=Self::CAUSE => self.mmu.map_or(0, |mut mmu| *Self::mmu_register(&mut mmu, index)),
+                number => self.data[number],
+            }),
+            None => Err(Error::InvalidRegister { number: index, instr_pointer: self.instr_pointer }),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new_with_count(Self::DEFAULT_DATA_REGISTERS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_sets_carry_on_unsigned_and_overflow_on_signed_wraparound() {
+        assert_eq!((0, Flags { zero: true, sign: false, carry: true, overflow: false }), Flags::of_add(-1, 1));
+        assert_eq!((Word::MIN, Flags { zero: false, sign: true, carry: false, overflow: true }), Flags::of_add(Word::MAX, 1));
+        assert_eq!((5, Flags::default()), Flags::of_add(2, 3));
+    }
+
+    #[test]
+    fn sub_sets_carry_on_borrow_and_overflow_on_signed_wraparound() {
+        assert_eq!((-1, Flags { zero: false, sign: true, carry: true, overflow: false }), Flags::of_sub(1, 2));
+        assert_eq!((Word::MAX, Flags { zero: false, sign: false, carry: false, overflow: true }), Flags::of_sub(Word::MIN, 1));
+        assert_eq!((0, Flags { zero: true, ..Flags::default() }), Flags::of_sub(7, 7));
+    }
+
+    #[test]
+    fn mult_sets_carry_and_overflow_when_the_product_does_not_fit() {
+        assert_eq!((6, Flags::default()), Flags::of_mult(2, 3));
+        let (_, flags) = Flags::of_mult(Word::MAX, 2);
+        assert!(flags.carry && flags.overflow);
+    }
+
+    #[test]
+    fn comparison_conditions_follow_signed_and_unsigned_order() {
+        let compare = |v1, v2| Flags::of_sub(v1, v2).1;
+
+        assert!(compare(3, -2).greater() && !compare(3, -2).above());
+        assert!(compare(-2, 3).less() && compare(-2, 3).above());
+        assert!(compare(4, 4).greater_or_equal() && compare(4, 4).less_or_equal());
+        assert!(!compare(4, 4).greater() && !compare(4, 4).less());
+        assert!(compare(Word::MIN, 1).less() && compare(1, Word::MIN).greater());
+        assert!(compare(1, 2).below() && !compare(2, 1).below());
+    }
+
+    #[test]
+    fn flags_round_trip_through_their_register_encoding() {
+        let flags = Flags { zero: false, sign: true, carry: true, overflow: false };
+        assert_eq!(0b0110, flags.to_word());
+        assert_eq!(flags, Flags::from_word(flags.to_word()));
+
+        let registers = Registers { flags, ..Registers::default() };
+        assert_eq!(0b0110, registers.read(Registers::FLAGS).unwrap());
+    }
+
+    #[test]
+    fn the_number_of_data_registers_is_configurable() {
+        let mut registers = Registers::new_with_count(32);
+        registers.write(31, 7).unwrap();
+        assert_eq!(7, registers.read(31).unwrap());
+        assert_eq!(Err(Error::InvalidRegister { number: 32, instr_pointer: 0 }), registers.read(32));
+
+        let registers = Registers::default();
+        assert_eq!(Registers::DEFAULT_DATA_REGISTERS, registers.data.len());
+        assert!(registers.read(15).is_ok());
+        assert!(matches!(registers.read(16), Err(Error::InvalidRegister { number: 16, .. })));
+
+        assert_eq!(Registers::MAX_DATA_REGISTERS, Registers::new_with_count(1000).data.len());
+    }
+
+    #[test]
+    fn special_registers_live_above_every_data_register() {
+        let mut registers = Registers::new_with_count(Registers::MAX_DATA_REGISTERS);
+        registers.write(Registers::MAX_DATA_REGISTERS - 1, 1).unwrap();
+        registers.write(Registers::STACK_POINTER, 2).unwrap();
+        assert_eq!(1, registers.read(Registers::MAX_DATA_REGISTERS - 1).unwrap());
+        assert_eq!(2, registers.read(Registers::STACK_POINTER).unwrap());
+
+        assert_eq!(Some(239), Registers::index_of("d239"));
+        assert_eq!(None, Registers::index_of("d240"));
+        assert_eq!(None, Registers::index_of("d+5"));
+        assert_eq!(None, Registers::index_of("d"));
+        assert_eq!("d239", Registers::name_of(239));
+        assert_eq!("ip", Registers::name_of(Registers::index_of("ip").unwrap()));
+        assert_eq!("at", Registers::name_of(Registers::index_of("at").unwrap()));
+    }
+
+    #[test]
+    fn each_special_register_has_its_own_permissions() {
+        let mut registers = Registers::default();
+        registers.flags.carry = true;
+
+        assert_eq!(Some(Access::ReadWrite), registers.access(Registers::INSTR_POINTER));
+        registers.write(Registers::INSTR_POINTER, 12).unwrap();
+        assert_eq!(12, registers.read(Registers::INSTR_POINTER).unwrap());
+
+        assert_eq!(Some(Access::ReadWrite), registers.access(Registers::STACK_POINTER));
+        registers.write(Registers::STACK_POINTER, 40).unwrap();
+        assert_eq!(40, registers.read(Registers::STACK_POINTER).unwrap());
+
+        assert_eq!(Some(Access::ReadOnly), registers.access(Registers::FLAGS));
+        assert_eq!(Err(Error::ReadOnlyRegister { number: Registers::FLAGS, instr_pointer: 12 }), registers.write(Registers::FLAGS, 0));
+        assert_eq!(Flags::CARRY_BIT, registers.read(Registers::FLAGS).unwrap());
+
+        assert_eq!(None, registers.access(Registers::FLAGS + 1));
+        assert!(matches!(registers.write(Registers::FLAGS + 1, 0), Err(Error::InvalidRegister { .. })));
+        assert!(matches!(registers.read(Registers::SCRATCH + 1), Err(Error::InvalidRegister { .. })));
+    }
+
+    #[test]
+    fn mmu_registers_exist_only_with_an_mmu() {
+        let mut registers = Registers::default();
+        assert_eq!(None, registers.access(Registers::PAGE_TABLE));
+        assert!(matches!(registers.write(Registers::TRAP_VECTOR, 1), Err(Error::InvalidRegister { number: 244, .. })));
+
+        let mut registers = Registers::default().with_mmu();
+        registers.write(Registers::PAGE_TABLE, 0x1001).unwrap();
+        registers.write(Registers::TRAP_POINTER, 3).unwrap();
+        assert_eq!(Some(Mmu { page_table: 0x1001, trap_pointer: 3, ..Mmu::default() }), registers.mmu);
+        assert_eq!(3, registers.read(Registers::TRAP_POINTER).unwrap());
+        assert_eq!(Some(Access::ReadOnly), registers.access(Registers::CAUSE));
+        assert!(matches!(registers.write(Registers::BAD_ADDRESS, 1), Err(Error::ReadOnlyRegister { number: 246, .. })));
+
+        assert_eq!(Some(Registers::TRAP_POINTER), Registers::index_of("epc"));
+        assert_eq!("badaddr", Registers::name_of(Registers::BAD_ADDRESS));
+    }
+}
@@ -1,14 +1,46 @@
 pub type Word = i64;
 
 use crate::util::pair_result;
-use crate::instruction::Instruction;
+use crate::instruction::{ Instruction, Width };
 use crate::error::{ Error, Result };
-use crate::memory::Memory;
-use crate::registers::Registers;
+use crate::memory::{ Memory, Operation, Permissions, Region };
+use crate::registers::{ Flags, Registers };
+use crate::device::Device;
+use crate::mmu::Mmu;
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+/*
+ * What signed overflow in Add, Sub, Mult, Inc, Dec and Div does: either the result wraps
+ * around in two's complement (and the overflow flag tells it happened), or the instruction
+ * faults with `Error::ArithmeticOverflow` leaving its destination untouched.
+ */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OverflowMode {
+    Wrap,
+    Trap,
+}
+
+/*
+ * Whether loads and stores must be aligned to their width. Instruction fetches and stack
+ * accesses are always word-aligned as long as ip and sp are used as intended.
+ */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AlignmentMode {
+    Allow,
+    Trap,
+}
 
 pub struct RuntimeBuilder {
     pub registers: Registers,
     pub memory: Memory,
+    pub instruction_limit: Option<u64>,
+    pub overflow_mode: OverflowMode,
+    pub alignment_mode: AlignmentMode,
+    pub devices: HashMap<u16, Box<dyn Device + Send>>,
+    pub regions: Vec<Region>,
+    pub mmu: bool,
 }
 
 impl RuntimeBuilder {
@@ -16,6 +48,12 @@ impl RuntimeBuilder {
         Self {
             registers: Registers::default(),
             memory: Memory::default(),
+            instruction_limit: None,
+            overflow_mode: OverflowMode::Wrap,
+            alignment_mode: AlignmentMode::Allow,
+            devices: HashMap::new(),
+            regions: Vec::new(),
+            mmu: false,
         }
     }
 
@@ -29,78 +67,418 @@ impl RuntimeBuilder {
         self
     }
 
+    /*
+     * Declares a named region of memory with its own permissions; see `Memory::with_region`.
+     * Regions are added to the memory when the runtime is built, after any it already has.
+     */
+    pub fn with_region(mut self, name: &str, addresses: Range<usize>, permissions: Permissions) -> Self {
+        self.regions.push(Region { name: name.to_string(), addresses, permissions });
+        self
+    }
+
+    /*
+     * Gives the runtime an MMU, see `Mmu`. Translation stays off until the guest turns it on
+     * through ptbr.
+     */
+    pub fn with_mmu(mut self) -> Self {
+        self.mmu = true;
+        self
+    }
+
     pub fn with_program(mut self, program: Vec<Word>) -> Self {
         for (index, inst) in program.iter().enumerate() {
-            self.memory.write(index as usize, *inst).expect("Error loading program");
+            self.memory.write_instruction(index, *inst).expect("Error loading program");
         }
         self
     }
 
-    pub fn build(self) -> Runtime {
+    /*
+     * Limits how many instructions may execute before the runtime stops with
+     * `ExitReason::OutOfFuel`. The budget can be topped up with `Runtime::refuel`.
+     */
+    pub fn with_instruction_limit(mut self, limit: u64) -> Self {
+        self.instruction_limit = Some(limit);
+        self
+    }
+
+    pub fn with_overflow_mode(mut self, overflow_mode: OverflowMode) -> Self {
+        self.overflow_mode = overflow_mode;
+        self
+    }
+
+    pub fn with_alignment_mode(mut self, alignment_mode: AlignmentMode) -> Self {
+        self.alignment_mode = alignment_mode;
+        self
+    }
+
+    /*
+     * Attaches `device` to `port`, replacing whatever was there. In and Out on a port with
+     * no device fault with `Error::UnmappedPort`.
+     */
+    pub fn with_device(mut self, port: u16, device: impl Device + Send + 'static) -> Self {
+        self.devices.insert(port, Box::new(device));
+        self
+    }
+
+    /*
+     * The stack pointer always starts at the top of the memory's stack region.
+     */
+    pub fn build(mut self) -> Runtime {
+        let memory = self.regions.into_iter().fold(self.memory, |memory, region| {
+            memory.with_region(&region.name, region.addresses, region.permissions)
+        });
+        if self.mmu {
+            self.registers.mmu.get_or_insert_with(Mmu::default);
+        }
+        self.registers.stack_pointer = memory.stack_region().end as Word;
         Runtime {
             registers: self.registers,
-            memory: self.memory,
-            flag_zero: false,
-            flag_carry: false,
+            memory,
             running: false,
+            fuel: self.instruction_limit,
+            overflow_mode: self.overflow_mode,
+            alignment_mode: self.alignment_mode,
+            devices: self.devices,
         }
     }
 }
 
+impl Default for RuntimeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExitReason {
+    Halted,
+    OutOfFuel,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Status {
+    Executed(Instruction),
+    Trapped { cause: Word, address: Word },
+    Exited(ExitReason),
+}
+
+/*
+ * Copy of the architectural state of a runtime at some point of its execution.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub struct RuntimeState {
+    pub data_registers: Vec<Word>,
+    pub instr_pointer: Word,
+    pub stack_pointer: Word,
+    pub flags: Flags,
+    pub scratch: Word,
+    pub mmu: Option<Mmu>,
+    pub running: bool,
+    pub remaining_fuel: Option<u64>,
+}
+
 pub struct Runtime {
     registers: Registers,
-    flag_zero: bool,
-    flag_carry: bool,
     memory: Memory,
     running: bool,
+    fuel: Option<u64>,
+    overflow_mode: OverflowMode,
+    alignment_mode: AlignmentMode,
+    devices: HashMap<u16, Box<dyn Device + Send>>,
 }
 
+/*
+ * Embedders may move a runtime, devices and memory included, to a worker thread.
+ */
+fn assert_send<T: Send>() {}
+const _: fn() = || assert_send::<Runtime>();
+
 impl Runtime {
-    fn read_next_inst(&self) -> Word {
+    fn read_next_inst(&self) -> Result<Word> {
         let current_ip = self.registers.instr_pointer as usize;
-        self.memory.read(current_ip).unwrap()
+        self.memory
+            .instruction_address(current_ip)
+            .and_then(|address| self.load(address, Memory::WORD_BYTES, Operation::Execute))
+            .map(|instruction| instruction as Word)
+            .map_err(|error| error.at(self.registers.instr_pointer))
     }
 
-    fn consume_next_instr(&mut self) -> Word {
-        let instruction = self.read_next_inst();
+    fn is_translating(&self) -> bool {
+        self.registers.mmu.is_some_and(|mmu| mmu.is_translating())
+    }
+
+    /*
+     * Physical (address, length) pieces of the `width` bytes at a guest address: one, or two
+     * when the access straddles a page boundary with translation on. Both pieces are checked
+     * before anything is transferred, so a faulting access has no effect.
+     */
+    fn physical(&self, address: usize, width: usize, operation: Operation) -> Result<[(usize, usize); 2]> {
+        let pieces = match self.registers.mmu {
+            Some(mmu) if mmu.is_translating() => {
+                let first = width.min(Mmu::PAGE_SIZE - address % Mmu::PAGE_SIZE);
+                let start = mmu.translate(&self.memory, address, operation)?;
+                let second = if first < width {
+                    mmu.translate(&self.memory, address.wrapping_add(first), operation)?
+                } else {
+                    0
+                };
+                [(start, first), (second, width - first)]
+            },
+            _ => [(address, width), (0, 0)],
+        };
+        for (address, width) in pieces {
+            self.memory.check(address, width, operation)?;
+        }
+        Ok(pieces)
+    }
+
+    fn load(&self, address: usize, width: usize, operation: Operation) -> Result<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        for (address, width) in self.physical(address, width, operation)? {
+            if width > 0 {
+                value |= self.memory.load(address, width)? << shift;
+                shift += 8 * width;
+            }
+        }
+        Ok(value)
+    }
+
+    fn store(&mut self, address: usize, width: usize, value: u64) -> Result<()> {
+        let mut shift = 0;
+        for (address, width) in self.physical(address, width, Operation::Write)? {
+            if width > 0 {
+                self.memory.store(address, width, value >> shift)?;
+                shift += 8 * width;
+            }
+        }
+        Ok(())
+    }
+
+    fn consume_next_instr(&mut self) -> Result<Word> {
+        let instruction = self.read_next_inst()?;
         self.registers.instr_pointer += 1;
-        instruction
-    }
-
-    fn perform_next_instr(&mut self) -> bool {
-        let instruction = self.consume_next_instr();
-
-        match Instruction::from(instruction) {
-            Instruction::Illegal                                  => self.handle_illegal_opcode().is_ok(),
-            Instruction::Halt                                     => false,
-            Instruction::Load { value, dest_reg }                 => self.perform_load(value, dest_reg).is_ok(),
-            Instruction::Copy { src, dest }                       => self.perform_copy(src, dest).is_ok(),
-            Instruction::Add { src1, src2, dest }                 => self.perform_add(src1, src2, dest).is_ok(),
-            Instruction::Sub { src1, src2, dest }                 => self.perform_sub(src1, src2, dest).is_ok(),
-            Instruction::Mult { src1, src2, dest }                => self.perform_mult(src1, src2, dest).is_ok(),
-            Instruction::Div { src1, src2, quot_dest, rem_dest }  => self.perform_div(src1, src2, quot_dest, rem_dest).is_ok(),
-            Instruction::Cmp { src1, src2 }                       => self.perform_cmp(src1, src2).is_ok(),
-            Instruction::Jmp { src }                              => self.perform_jmp(src).is_ok(),
-            Instruction::Jz { src }                               => self.perform_jz(src).is_ok(),
-            Instruction::Jnz { src }                              => self.perform_jnz(src).is_ok(),
-            Instruction::Jgt { src }                              => self.perform_jgt(src).is_ok(),
-            Instruction::Jlt { src }                              => self.perform_jlt(src).is_ok(),
-            Instruction::Inc { dest }                             => self.perform_inc(dest).is_ok(),
-            Instruction::Dec { dest }                             => self.perform_dec(dest).is_ok(),
-            Instruction::LoadMem { src_addr, dest_reg }           => self.perform_load_mem(src_addr, dest_reg).is_ok(),
-            Instruction::StoreMem { src_reg, dest_addr }          => self.perform_store_mem(src_reg, dest_addr).is_ok(),
+        Ok(instruction)
+    }
+
+    /*
+     * Executes the instruction at the instruction pointer. A failing instruction leaves the
+     * instruction pointer at its own address.
+     */
+    fn perform_next_instr(&mut self) -> Result<Status> {
+        let instr_pointer = self.registers.instr_pointer;
+        let instruction = self.consume_next_instr()?;
+
+        let decoded = Instruction::from(instruction);
+        let flags = self.registers.flags;
+        let result = match decoded {
+            Instruction::Illegal                                  => Err(Error::IllegalOpcode { instruction, instr_pointer }),
+            Instruction::Halt                                     => return Ok(Status::Exited(ExitReason::Halted)),
+            Instruction::Load { value, dest_reg }                 => self.perform_load(value, dest_reg),
+            Instruction::Copy { src, dest }                       => self.perform_copy(src, dest),
+            Instruction::Add { src1, src2, dest }                 => self.perform_add(src1, src2, dest),
+            Instruction::Sub { src1, src2, dest }                 => self.perform_sub(src1, src2, dest),
+            Instruction::Mult { src1, src2, dest }                => self.perform_mult(src1, src2, dest),
+            Instruction::Div { src1, src2, quot_dest, rem_dest }  => self.perform_div(src1, src2, quot_dest, rem_dest),
+            Instruction::Cmp { src1, src2 }                       => self.perform_cmp(src1, src2),
+            Instruction::Jmp { src }                              => self.perform_jump_if(true, src),
+            Instruction::Jz { src }                               => self.perform_jump_if(flags.zero, src),
+            Instruction::Jnz { src }                              => self.perform_jump_if(!flags.zero, src),
+            Instruction::Jgt { src }                              => self.perform_jump_if(flags.greater(), src),
+            Instruction::Jlt { src }                              => self.perform_jump_if(flags.less(), src),
+            Instruction::Jge { src }                              => self.perform_jump_if(flags.greater_or_equal(), src),
+            Instruction::Jle { src }                              => self.perform_jump_if(flags.less_or_equal(), src),
+            Instruction::Ja { src }                               => self.perform_jump_if(flags.above(), src),
+            Instruction::Jb { src }                               => self.perform_jump_if(flags.below(), src),
+            Instruction::Jo { src }                               => self.perform_jump_if(flags.overflow, src),
+            Instruction::Js { src }                               => self.perform_jump_if(flags.sign, src),
+            Instruction::Inc { dest }                             => self.perform_inc(dest),
+            Instruction::Dec { dest }                             => self.perform_dec(dest),
+            Instruction::LoadMem { src_addr, dest_reg, width, unsigned } => self.perform_load_mem(src_addr, dest_reg, width, unsigned),
+            Instruction::StoreMem { src_reg, dest_addr, width }   => self.perform_store_mem(src_reg, dest_addr, width),
+            Instruction::Push { src }                             => self.perform_push(src),
+            Instruction::Pop { dest }                             => self.perform_pop(dest),
+            Instruction::Call { src }                             => self.perform_call(src),
+            Instruction::Ret                                      => self.perform_ret(),
+            Instruction::TrapReturn                               => self.perform_trap_return(instruction),
+            Instruction::And { src1, src2, dest }                 => self.perform_binary(src1, src2, dest, |v1, v2| v1 & v2),
+            Instruction::Or { src1, src2, dest }                  => self.perform_binary(src1, src2, dest, |v1, v2| v1 | v2),
+            Instruction::Xor { src1, src2, dest }                 => self.perform_binary(src1, src2, dest, |v1, v2| v1 ^ v2),
+            Instruction::Not { src, dest }                        => self.perform_not(src, dest),
+            Instruction::Shl { src1, src2, dest }                 => self.perform_binary(src1, src2, dest, Self::shift_left),
+            Instruction::Shr { src1, src2, dest }                 => self.perform_binary(src1, src2, dest, Self::shift_right_logical),
+            Instruction::Sar { src1, src2, dest }                 => self.perform_binary(src1, src2, dest, Self::shift_right_arithmetic),
+            Instruction::AddI { src, imm, dest }                  => self.perform_arith_imm(src, imm, dest, Flags::of_add),
+            Instruction::SubI { src, imm, dest }                  => self.perform_arith_imm(src, imm, dest, Flags::of_sub),
+            Instruction::MultI { src, imm, dest }                 => self.perform_arith_imm(src, imm, dest, Flags::of_mult),
+            Instruction::CmpI { src, imm }                        => self.perform_cmp_imm(src, imm),
+            Instruction::Br { offset }                            => self.perform_branch_if(true, instr_pointer, offset),
+            Instruction::Brz { offset }                           => self.perform_branch_if(flags.zero, instr_pointer, offset),
+            Instruction::Brnz { offset }                          => self.perform_branch_if(!flags.zero, instr_pointer, offset),
+            Instruction::Brgt { offset }                          => self.perform_branch_if(flags.greater(), instr_pointer, offset),
+            Instruction::Brlt { offset }                          => self.perform_branch_if(flags.less(), instr_pointer, offset),
+            Instruction::Brge { offset }                          => self.perform_branch_if(flags.greater_or_equal(), instr_pointer, offset),
+            Instruction::Brle { offset }                          => self.perform_branch_if(flags.less_or_equal(), instr_pointer, offset),
+            Instruction::Bra { offset }                           => self.perform_branch_if(flags.above(), instr_pointer, offset),
+            Instruction::Brb { offset }                           => self.perform_branch_if(flags.below(), instr_pointer, offset),
+            Instruction::Bro { offset }                           => self.perform_branch_if(flags.overflow, instr_pointer, offset),
+            Instruction::Brs { offset }                           => self.perform_branch_if(flags.sign, instr_pointer, offset),
+            Instruction::LoadHigh { value, dest_reg }             => self.perform_load_high(value, dest_reg),
+            Instruction::In { port, dest }                        => self.perform_in(port, dest),
+            Instruction::Out { src, port }                        => self.perform_out(src, port),
+            Instruction::LoadMemIndexed { base, offset, dest_reg, width, unsigned } => self
+                .effective_address(base, offset)
+                .and_then(|src_addr| self.perform_load_mem(src_addr, dest_reg, width, unsigned)),
+            Instruction::StoreMemIndexed { src_reg, base, offset, width } => self
+                .effective_address(base, offset)
+                .and_then(|dest_addr| self.perform_store_mem(src_reg, dest_addr, width)),
+        };
+
+        result
+            .map(|()| Status::Executed(decoded))
+            .map_err(|error| {
+                self.registers.instr_pointer = instr_pointer;
+                error.at(instr_pointer)
+            })
+    }
+
+    /*
+     * Executes a single instruction. On a fault the error is returned and the instruction
+     * pointer is left at the faulting instruction.
+     */
+    pub fn step(&mut self) -> Result<Status> {
+        if self.fuel == Some(0) {
+            self.running = false;
+            return Ok(Status::Exited(ExitReason::OutOfFuel));
+        }
+        let status = match self.perform_next_instr() {
+            Err(Error::PageFault { address, operation, instr_pointer: Some(instr_pointer) }) if self.has_trap_handler() =>
+                Ok(self.deliver_trap(address, operation, instr_pointer)),
+            result => result,
+        };
+        let status = status.inspect_err(|_| self.running = false)?;
+        self.fuel = self.fuel.map(|fuel| fuel - 1);
+        self.running = !matches!(status, Status::Exited(_));
+        Ok(status)
+    }
+
+    fn has_trap_handler(&self) -> bool {
+        self.registers.mmu.is_some_and(|mmu| mmu.has_handler())
+    }
+
+    fn deliver_trap(&mut self, address: Word, operation: Operation, instr_pointer: Word) -> Status {
+        let mmu = self.registers.mmu.as_mut().expect("traps are only delivered with an MMU");
+        mmu.enter_trap(instr_pointer, address, operation);
+        self.registers.instr_pointer = mmu.trap_vector;
+        Status::Trapped { cause: mmu.cause, address }
+    }
+
+    /*
+     * Instructions left before the runtime runs out of fuel, if it was given a limit.
+     */
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /*
+     * Replaces the remaining instruction budget so that a runtime which ran out of fuel
+     * can be resumed.
+     */
+    pub fn refuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    pub fn register(&self, index: usize) -> Result<Word> {
+        self.registers.read(index)
+    }
+
+    pub fn register_by_name(&self, name: &str) -> Result<Word> {
+        Registers::index_of(name)
+            .ok_or_else(|| Error::UnknownRegister { name: name.to_string() })
+            .and_then(|index| self.registers.read(index))
+    }
+
+    pub fn instr_pointer(&self) -> Word {
+        self.registers.instr_pointer
+    }
+
+    pub fn stack_pointer(&self) -> Word {
+        self.registers.stack_pointer
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.registers.flags
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /*
+     * Reads the words stored in a range of byte addresses.
+     */
+    pub fn read_memory(&self, addresses: Range<usize>) -> Result<Vec<Word>> {
+        self.memory.read_range(addresses)
+    }
+
+    pub fn snapshot(&self) -> RuntimeState {
+        RuntimeState {
+            data_registers: self.registers.data.clone(),
+            instr_pointer: self.registers.instr_pointer,
+            stack_pointer: self.registers.stack_pointer,
+            flags: self.registers.flags,
+            scratch: self.registers.scratch,
+            mmu: self.registers.mmu,
+            running: self.running,
+            remaining_fuel: self.fuel,
+        }
+    }
+
+    /*
+     * Executes up to `n` instructions, returning the exit reason if the program stopped
+     * before all of them ran.
+     */
+    pub fn step_n(&mut self, n: usize) -> Result<Option<ExitReason>> {
+        for _ in 0..n {
+            if let Status::Exited(reason) = self.step()? {
+                return Ok(Some(reason));
+            }
         }
+        Ok(None)
     }
 
-    pub fn run(&mut self) {
-        self.running = true;
-        while self.running {
-            self.running = self.perform_next_instr();
+    /*
+     * Executes instructions until `predicate` holds after one of them (at least one is always
+     * executed, so a breakpoint on the current instruction does not stop immediately) or the
+     * program stops, in which case the exit reason is returned.
+     */
+    pub fn run_until<P>(&mut self, mut predicate: P) -> Result<Option<ExitReason>>
+    where
+        P: FnMut(&Runtime) -> bool,
+    {
+        loop {
+            if let Status::Exited(reason) = self.step()? {
+                return Ok(Some(reason));
+            }
+            if predicate(self) {
+                return Ok(None);
+            }
         }
     }
 
-    fn handle_illegal_opcode(&self) -> Result<()> {
-        Err(Error::IllegalOpcode { instruction: self.memory.read(self.registers.instr_pointer as usize).unwrap(), instr_pointer: self.registers.instr_pointer })
+    /*
+     * Runs until the program halts or faults.
+     */
+    pub fn run(&mut self) -> Result<ExitReason> {
+        loop {
+            if let Status::Exited(reason) = self.step()? {
+                return Ok(reason);
+            }
+        }
     }
 
     fn perform_load(&mut self, value: Word, dest_reg: u8) -> Result<()> {
@@ -108,6 +486,14 @@ impl Runtime {
             .write(dest_reg as usize, value)
     }
 
+    fn perform_load_high(&mut self, value: Word, dest_reg: u8) -> Result<()> {
+        const LOW_BITS: u32 = 46;
+        let low_mask = (1 << LOW_BITS) - 1;
+        self.registers
+            .read(dest_reg as usize)
+            .and_then(|current| self.registers.write(dest_reg as usize, (value << LOW_BITS) | (current & low_mask)))
+    }
+
     fn perform_copy(&mut self, src: u8, dest: u8) -> Result<()> {
         self.registers
             .read(src as usize)
@@ -117,19 +503,34 @@ impl Runtime {
     fn perform_add(&mut self, src1: u8, src2: u8, dest: u8) -> Result<()> {
         let res1 = self.registers.read(src1 as usize);
         let res2 = self.registers.read(src2 as usize);
-        pair_result(res1, res2).and_then(|(v1, v2)| self.registers.write(dest as usize, v1 + v2))
+        pair_result(res1, res2).and_then(|(v1, v2)| self.write_with_flags(dest, Flags::of_add(v1, v2)))
     }
 
     fn perform_sub(&mut self, src1: u8, src2: u8, dest: u8) -> Result<()> {
         let res1 = self.registers.read(src1 as usize);
         let res2 = self.registers.read(src2 as usize);
-        pair_result(res1, res2).and_then(|(v1, v2)| self.registers.write(dest as usize, v1 - v2))
+        pair_result(res1, res2).and_then(|(v1, v2)| self.write_with_flags(dest, Flags::of_sub(v1, v2)))
     }
 
     fn perform_mult(&mut self, src1: u8, src2: u8, dest: u8) -> Result<()> {
         let res1 = self.registers.read(src1 as usize);
         let res2 = self.registers.read(src2 as usize);
-        pair_result(res1, res2).and_then(|(v1, v2)| self.registers.write(dest as usize, v1 * v2))
+        pair_result(res1, res2).and_then(|(v1, v2)| self.write_with_flags(dest, Flags::of_mult(v1, v2)))
+    }
+
+    fn write_with_flags(&mut self, dest: u8, (result, flags): (Word, Flags)) -> Result<()> {
+        self.check_overflow(flags.overflow)?;
+        self.registers
+            .write(dest as usize, result)
+            .map(|()| self.registers.flags = flags)
+    }
+
+    fn check_overflow(&self, overflow: bool) -> Result<()> {
+        if overflow && self.overflow_mode == OverflowMode::Trap {
+            Err(Error::ArithmeticOverflow { instr_pointer: self.registers.instr_pointer })
+        } else {
+            Ok(())
+        }
     }
 
     fn perform_div(&mut self, src1: u8, src2: u8, quot_dest: u8, rem_dest: u8) -> Result<()> {
@@ -139,9 +540,12 @@ impl Runtime {
             if v2 == 0 {
                 return Err(Error::DivisionByZero { instr_pointer: self.registers.instr_pointer });
             }
+            let (quotient, flags) = Flags::of_div(v1, v2);
+            self.check_overflow(flags.overflow)?;
             self.registers
-                .write(quot_dest as usize, v1 / v2)
-                .and_then(|()| self.registers.write(rem_dest as usize, v1 % v2))
+                .write(quot_dest as usize, quotient)
+                .and_then(|()| self.registers.write(rem_dest as usize, v1.wrapping_rem(v2)))
+                .map(|()| self.registers.flags = flags)
         })
     }
 
@@ -149,39 +553,26 @@ impl Runtime {
         let res1 = self.registers.read(src1 as usize);
         let res2 = self.registers.read(src2 as usize);
         pair_result(res1, res2).map(|(v1, v2)| {
-            self.flag_zero = v1 == v2;
-            self.flag_carry = v1 < v2;
+            let (_, flags) = Flags::of_sub(v1, v2);
+            self.registers.flags = flags;
         })
     }
 
-    fn perform_jmp(&mut self, src: u8) -> Result<()> {
+    fn perform_arith_imm(&mut self, src: u8, imm: Word, dest: u8, operation: fn(Word, Word) -> (Word, Flags)) -> Result<()> {
         self.registers
             .read(src as usize)
-            .map(|v| self.registers.instr_pointer = v)
-    }
-
-    fn perform_jz(&mut self, src: u8) -> Result<()> {
-        if self.flag_zero {
-            self.registers
-                .read(src as usize)
-                .map(|v| self.registers.instr_pointer = v)
-        } else {
-            Ok(())
-        }
+            .and_then(|value| self.write_with_flags(dest, operation(value, imm)))
     }
 
-    fn perform_jnz(&mut self, src: u8) -> Result<()> {
-        if !self.flag_zero {
-            self.registers
-                .read(src as usize)
-                .map(|v| self.registers.instr_pointer = v)
-        } else {
-            Ok(())
-        }
+    fn perform_cmp_imm(&mut self, src: u8, imm: Word) -> Result<()> {
+        self.registers.read(src as usize).map(|value| {
+            let (_, flags) = Flags::of_sub(value, imm);
+            self.registers.flags = flags;
+        })
     }
 
-    fn perform_jgt(&mut self, src: u8) -> Result<()> {
-        if !self.flag_carry {
+    fn perform_jump_if(&mut self, condition: bool, src: u8) -> Result<()> {
+        if condition {
             self.registers
                 .read(src as usize)
                 .map(|v| self.registers.instr_pointer = v)
@@ -190,50 +581,209 @@ impl Runtime {
         }
     }
 
-    fn perform_jlt(&mut self, src: u8) -> Result<()> {
-        if self.flag_carry {
-            self.registers
-                .read(src as usize)
-                .map(|v| self.registers.instr_pointer = v)
-        } else {
-            Ok(())
+    fn perform_branch_if(&mut self, condition: bool, branch_address: Word, offset: Word) -> Result<()> {
+        if condition {
+            self.registers.instr_pointer = branch_address.wrapping_add(offset);
         }
+        Ok(())
     }
 
     fn perform_inc(&mut self, dest: u8) -> Result<()> {
         self.registers
             .read(dest as usize)
-            .and_then(|current_value| self.registers.write(dest as usize, current_value + 1))
+            .and_then(|current_value| self.write_with_flags(dest, Flags::of_add(current_value, 1)))
     }
 
     fn perform_dec(&mut self, dest: u8) -> Result<()> {
         self.registers
             .read(dest as usize)
-            .and_then(|current_value| self.registers.write(dest as usize, current_value - 1))
+            .and_then(|current_value| self.write_with_flags(dest, Flags::of_sub(current_value, 1)))
+    }
+
+    fn check_alignment(&self, address: Word, width: Width) -> Result<()> {
+        if self.alignment_mode == AlignmentMode::Trap && address % width.bytes() as Word != 0 {
+            Err(Error::MisalignedAccess { address, width: width.bytes(), instr_pointer: self.registers.instr_pointer })
+        } else {
+            Ok(())
+        }
     }
 
-    fn perform_load_mem(&mut self, src_addr: Word, dest_reg: u8) -> Result<()> {
-        self.registers.write(dest_reg as usize, self.memory.read(src_addr as usize).unwrap())
+    fn perform_load_mem(&mut self, src_addr: Word, dest_reg: u8, width: Width, unsigned: bool) -> Result<()> {
+        self.check_alignment(src_addr, width)?;
+        let unused = Word::BITS as usize - 8 * width.bytes();
+        let value = self.load(src_addr as usize, width.bytes(), Operation::Read)? as Word;
+        let extended = if unsigned { value } else { (value << unused) >> unused };
+        self.registers.write(dest_reg as usize, extended)
     }
 
-    fn perform_store_mem(&mut self, src_reg: u8, dest_addr: Word) -> Result<()> {
+    fn perform_store_mem(&mut self, src_reg: u8, dest_addr: Word, width: Width) -> Result<()> {
+        self.check_alignment(dest_addr, width)?;
         self.registers
             .read(src_reg as usize)
-            .map(|value| self.memory.write(dest_addr as usize, value).unwrap())
+            .and_then(|value| self.store(dest_addr as usize, width.bytes(), value as u64))
+    }
+
+    /*
+     * Addresses computed below zero wrap to huge values, so they are reported as out of
+     * bounds like any other bad address.
+     */
+    fn effective_address(&self, base: u8, offset: Word) -> Result<Word> {
+        self.registers
+            .read(base as usize)
+            .map(|base_value| base_value.wrapping_add(offset))
+    }
+
+    fn device(&mut self, port: u16) -> Result<&mut Box<dyn Device + Send>> {
+        let instr_pointer = self.registers.instr_pointer;
+        self.devices
+            .get_mut(&port)
+            .ok_or(Error::UnmappedPort { port, instr_pointer })
+    }
+
+    fn perform_in(&mut self, port: u16, dest: u8) -> Result<()> {
+        let instr_pointer = self.registers.instr_pointer;
+        let value = self
+            .device(port)?
+            .read()
+            .map_err(|error| Error::DeviceFailure { port, message: error.to_string(), instr_pointer })?;
+        self.registers.write(dest as usize, value)
+    }
+
+    fn perform_out(&mut self, src: u8, port: u16) -> Result<()> {
+        let instr_pointer = self.registers.instr_pointer;
+        let value = self.registers.read(src as usize)?;
+        self.device(port)?
+            .write(value)
+            .map_err(|error| Error::DeviceFailure { port, message: error.to_string(), instr_pointer })
+    }
+
+    fn perform_binary(&mut self, src1: u8, src2: u8, dest: u8, operation: fn(Word, Word) -> Word) -> Result<()> {
+        let res1 = self.registers.read(src1 as usize);
+        let res2 = self.registers.read(src2 as usize);
+        pair_result(res1, res2).and_then(|(v1, v2)| self.registers.write(dest as usize, operation(v1, v2)))
+    }
+
+    fn perform_not(&mut self, src: u8, dest: u8) -> Result<()> {
+        self.registers
+            .read(src as usize)
+            .and_then(|value| self.registers.write(dest as usize, !value))
+    }
+
+    /*
+     * Shift amounts are taken modulo the word size (only their low 6 bits are used). Logical
+     * right shifts fill with zeros, arithmetic ones replicate the sign bit.
+     */
+    fn shift_left(value: Word, amount: Word) -> Word {
+        value.wrapping_shl(amount as u32)
+    }
+
+    fn shift_right_logical(value: Word, amount: Word) -> Word {
+        (value as u64).wrapping_shr(amount as u32) as Word
+    }
+
+    fn shift_right_arithmetic(value: Word, amount: Word) -> Word {
+        value.wrapping_shr(amount as u32)
+    }
+
+    /*
+     * The stack grows downwards: the stack pointer holds the address of the last pushed
+     * word, or the end of the stack region when it is empty. With translation on, sp is a
+     * virtual address and the page table rather than the stack region bounds the stack.
+     */
+    fn push(&mut self, value: Word) -> Result<()> {
+        let stack_pointer = self.registers.stack_pointer;
+        let stack = self.memory.stack_region();
+        let address = stack_pointer.wrapping_sub(Memory::WORD_BYTES as Word);
+        if !self.is_translating() && (address < stack.start as Word || address >= stack.end as Word) {
+            return Err(Error::StackOverflow { stack_pointer, instr_pointer: self.registers.instr_pointer });
+        }
+        self.store(address as usize, Memory::WORD_BYTES, value as u64)
+            .map(|()| self.registers.stack_pointer = address)
+    }
+
+    fn top(&self) -> Result<Word> {
+        let stack_pointer = self.registers.stack_pointer;
+        let stack = self.memory.stack_region();
+        if !self.is_translating() && (stack_pointer < stack.start as Word || stack_pointer >= stack.end as Word) {
+            return Err(Error::StackUnderflow { stack_pointer, instr_pointer: self.registers.instr_pointer });
+        }
+        self.load(stack_pointer as usize, Memory::WORD_BYTES, Operation::Read)
+            .map(|value| value as Word)
+    }
+
+    fn pop(&mut self) -> Result<Word> {
+        self.top().inspect(|_| self.registers.stack_pointer += Memory::WORD_BYTES as Word)
+    }
+
+    fn perform_push(&mut self, src: u8) -> Result<()> {
+        self.registers
+            .read(src as usize)
+            .and_then(|value| self.push(value))
+    }
+
+    /*
+     * The destination is written before sp moves, so popping into a register that cannot
+     * be written faults with the stack untouched. Popping into sp replaces it outright.
+     */
+    fn perform_pop(&mut self, dest: u8) -> Result<()> {
+        let value = self.top()?;
+        self.registers.write(dest as usize, value)?;
+        if dest as usize != Registers::STACK_POINTER {
+            self.registers.stack_pointer += Memory::WORD_BYTES as Word;
+        }
+        Ok(())
+    }
+
+    fn perform_call(&mut self, src: u8) -> Result<()> {
+        let target = self.registers.read(src as usize)?;
+        self.push(self.registers.instr_pointer)
+            .map(|()| self.registers.instr_pointer = target)
+    }
+
+    fn perform_ret(&mut self) -> Result<()> {
+        self.pop().map(|address| self.registers.instr_pointer = address)
+    }
+
+    /*
+     * Returns from a page-fault handler to the instruction at epc, turning translation back
+     * on. Without an MMU there is nothing to return from.
+     */
+    fn perform_trap_return(&mut self, instruction: Word) -> Result<()> {
+        let instr_pointer = self.registers.instr_pointer - 1;
+        let mmu = self.registers.mmu.as_mut().ok_or(Error::IllegalOpcode { instruction, instr_pointer })?;
+        self.registers.instr_pointer = mmu.leave_trap();
+        Ok(())
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)] // binary literals are grouped by instruction field
 mod tests {
     use super::*;
+    use crate::device::Capture;
+
+    fn run_source(source: &str) -> Runtime {
+        let (vm, result) = run_with(RuntimeBuilder::new(), source);
+        result.unwrap();
+        vm
+    }
+
+    fn run_with(builder: RuntimeBuilder, source: &str) -> (Runtime, Result<ExitReason>) {
+        let program = crate::assembler::assemble(source).unwrap();
+        let mut vm = builder
+            .with_program(program)
+            .build();
+        let result = vm.run();
+        (vm, result)
+    }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn brand_new_runtime_has_default_values() {
         let vm = RuntimeBuilder::new()
             .build();
         
-        assert_eq!(vm.flag_zero, false);
-        assert_eq!(vm.flag_carry, false);
+        assert_eq!(Flags::default(), vm.registers.flags);
         assert_eq!(vm.running, false);
     }
 
@@ -244,15 +794,15 @@ mod tests {
             .with_program(program)
             .build();
 
-        let instruction = vm.consume_next_instr();
+        let instruction = vm.consume_next_instr().unwrap();
         let expected = 7;
         assert_eq!(expected, instruction);
 
-        let instruction = vm.consume_next_instr();
+        let instruction = vm.consume_next_instr().unwrap();
         let expected = 8;
         assert_eq!(expected, instruction);
 
-        let instruction = vm.consume_next_instr();
+        let instruction = vm.consume_next_instr().unwrap();
         let expected = 9;
         assert_eq!(expected, instruction);
     }
@@ -278,32 +828,32 @@ mod tests {
             .with_program(program)
             .build();
 
-        vm.perform_next_instr();
-        assert_eq!(expected_d0, vm.registers.data0);
-        assert_eq!(0, vm.registers.data1);
-        assert_eq!(0, vm.registers.data2);
-        assert_eq!(0, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(expected_d0, vm.registers.data[0]);
+        assert_eq!(0, vm.registers.data[1]);
+        assert_eq!(0, vm.registers.data[2]);
+        assert_eq!(0, vm.registers.data[3]);
         assert_eq!(1, vm.registers.instr_pointer);
 
-        vm.perform_next_instr();
-        assert_eq!(expected_d0, vm.registers.data0);
-        assert_eq!(expected_d1, vm.registers.data1);
-        assert_eq!(0, vm.registers.data2);
-        assert_eq!(0, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(expected_d0, vm.registers.data[0]);
+        assert_eq!(expected_d1, vm.registers.data[1]);
+        assert_eq!(0, vm.registers.data[2]);
+        assert_eq!(0, vm.registers.data[3]);
         assert_eq!(2, vm.registers.instr_pointer);
 
-        vm.perform_next_instr();
-        assert_eq!(expected_d0, vm.registers.data0);
-        assert_eq!(expected_d1, vm.registers.data1);
-        assert_eq!(expected_d2, vm.registers.data2);
-        assert_eq!(0, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(expected_d0, vm.registers.data[0]);
+        assert_eq!(expected_d1, vm.registers.data[1]);
+        assert_eq!(expected_d2, vm.registers.data[2]);
+        assert_eq!(0, vm.registers.data[3]);
         assert_eq!(3, vm.registers.instr_pointer);
 
-        vm.perform_next_instr();
-        assert_eq!(expected_d0, vm.registers.data0);
-        assert_eq!(expected_d1, vm.registers.data1);
-        assert_eq!(expected_d2, vm.registers.data2);
-        assert_eq!(expected_d3, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(expected_d0, vm.registers.data[0]);
+        assert_eq!(expected_d1, vm.registers.data[1]);
+        assert_eq!(expected_d2, vm.registers.data[2]);
+        assert_eq!(expected_d3, vm.registers.data[3]);
         assert_eq!(4, vm.registers.instr_pointer);
     }
 
@@ -317,16 +867,16 @@ mod tests {
             .with_program(program)
             .build();
 
-        assert_eq!(0, vm.registers.data0);
-        assert_eq!(0, vm.registers.data1);
+        assert_eq!(0, vm.registers.data[0]);
+        assert_eq!(0, vm.registers.data[1]);
 
-        vm.perform_next_instr();  // load $17, d0
-        assert_eq!(17, vm.registers.data0);
-        assert_eq!(0, vm.registers.data1);
+        vm.step().unwrap();  // load $17, d0
+        assert_eq!(17, vm.registers.data[0]);
+        assert_eq!(0, vm.registers.data[1]);
 
-        vm.perform_next_instr();  // copy d0, d1
-        assert_eq!(17, vm.registers.data0);
-        assert_eq!(17, vm.registers.data1);
+        vm.step().unwrap();  // copy d0, d1
+        assert_eq!(17, vm.registers.data[0]);
+        assert_eq!(17, vm.registers.data[1]);
     }
 
     #[test]
@@ -345,20 +895,20 @@ mod tests {
             .with_program(program)
             .build();
 
-        vm.perform_next_instr();
-        assert_eq!(0b11111010000, vm.registers.data0);
-        assert_eq!(0, vm.registers.data1);
-        assert_eq!(0, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(0b11111010000, vm.registers.data[0]);
+        assert_eq!(0, vm.registers.data[1]);
+        assert_eq!(0, vm.registers.data[3]);
 
-        vm.perform_next_instr();
-        assert_eq!(0b11111010000, vm.registers.data0);
-        assert_eq!(0b101110111000, vm.registers.data1);
-        assert_eq!(0, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(0b11111010000, vm.registers.data[0]);
+        assert_eq!(0b101110111000, vm.registers.data[1]);
+        assert_eq!(0, vm.registers.data[3]);
 
-        vm.perform_next_instr();
-        assert_eq!(0b11111010000, vm.registers.data0);
-        assert_eq!(0b101110111000, vm.registers.data1);
-        assert_eq!(expected_result, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(0b11111010000, vm.registers.data[0]);
+        assert_eq!(0b101110111000, vm.registers.data[1]);
+        assert_eq!(expected_result, vm.registers.data[3]);
     }
 
     #[test]
@@ -377,20 +927,20 @@ mod tests {
             .with_program(program)
             .build();
 
-        vm.perform_next_instr();
-        assert_eq!(0b11111010000, vm.registers.data0);
-        assert_eq!(0, vm.registers.data1);
-        assert_eq!(0, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(0b11111010000, vm.registers.data[0]);
+        assert_eq!(0, vm.registers.data[1]);
+        assert_eq!(0, vm.registers.data[3]);
 
-        vm.perform_next_instr();
-        assert_eq!(0b11111010000, vm.registers.data0);
-        assert_eq!(0b101110111000, vm.registers.data1);
-        assert_eq!(0, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(0b11111010000, vm.registers.data[0]);
+        assert_eq!(0b101110111000, vm.registers.data[1]);
+        assert_eq!(0, vm.registers.data[3]);
 
-        vm.perform_next_instr();
-        assert_eq!(0b11111010000, vm.registers.data0);
-        assert_eq!(0b101110111000, vm.registers.data1);
-        assert_eq!(expected_result, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(0b11111010000, vm.registers.data[0]);
+        assert_eq!(0b101110111000, vm.registers.data[1]);
+        assert_eq!(expected_result, vm.registers.data[3]);
     }
 
     #[test]
@@ -410,20 +960,20 @@ mod tests {
             .with_program(program)
             .build();
 
-        vm.perform_next_instr();
-        assert_eq!(0b11111010000, vm.registers.data0);
-        assert_eq!(0, vm.registers.data1);
-        assert_eq!(0, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(0b11111010000, vm.registers.data[0]);
+        assert_eq!(0, vm.registers.data[1]);
+        assert_eq!(0, vm.registers.data[3]);
 
-        vm.perform_next_instr();
-        assert_eq!(0b11111010000, vm.registers.data0);
-        assert_eq!(0b101110111000, vm.registers.data1);
-        assert_eq!(0, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(0b11111010000, vm.registers.data[0]);
+        assert_eq!(0b101110111000, vm.registers.data[1]);
+        assert_eq!(0, vm.registers.data[3]);
 
-        vm.perform_next_instr();
-        assert_eq!(0b11111010000, vm.registers.data0);
-        assert_eq!(0b101110111000, vm.registers.data1);
-        assert_eq!(expected_result, vm.registers.data3);
+        vm.step().unwrap();
+        assert_eq!(0b11111010000, vm.registers.data[0]);
+        assert_eq!(0b101110111000, vm.registers.data[1]);
+        assert_eq!(expected_result, vm.registers.data[3]);
     }
 
     #[test]
@@ -440,12 +990,12 @@ mod tests {
             .with_program(program)
             .build();
 
-        vm.perform_next_instr();  // load $4321, d0
-        vm.perform_next_instr();  // load $1234, d1
-        vm.perform_next_instr();  // div d0 d1 d2 d3
+        vm.step().unwrap();  // load $4321, d0
+        vm.step().unwrap();  // load $1234, d1
+        vm.step().unwrap();  // div d0 d1 d2 d3
 
-        assert_eq!(expected_quotient, vm.registers.data2);
-        assert_eq!(expected_remainder, vm.registers.data3);
+        assert_eq!(expected_quotient, vm.registers.data[2]);
+        assert_eq!(expected_remainder, vm.registers.data[3]);
     }
 
     #[test]
@@ -462,18 +1012,18 @@ mod tests {
             .with_program(program)
             .build();
 
-        vm.perform_next_instr();  // load $2000, d0
-        vm.perform_next_instr();  // load $3000, d1
-        vm.perform_next_instr();  // load $2000, d2
+        vm.step().unwrap();  // load $2000, d0
+        vm.step().unwrap();  // load $3000, d1
+        vm.step().unwrap();  // load $2000, d2
 
-        vm.perform_next_instr();  // cmp d0, d1
-        assert!(!vm.flag_zero);
+        vm.step().unwrap();  // cmp d0, d1
+        assert!(!vm.registers.flags.zero);
 
-        vm.perform_next_instr();  // cmp d0, d2
-        assert!(vm.flag_zero);
+        vm.step().unwrap();  // cmp d0, d2
+        assert!(vm.registers.flags.zero);
 
-        vm.perform_next_instr();  // cmp d1, d0
-        assert!(!vm.flag_zero);
+        vm.step().unwrap();  // cmp d1, d0
+        assert!(!vm.registers.flags.zero);
     }
 
     #[test]
@@ -490,44 +1040,44 @@ mod tests {
             .build();
 
         assert_eq!(0, vm.registers.instr_pointer);
-        assert_eq!(0, vm.registers.data0);
-        assert_eq!(0, vm.registers.data1);
+        assert_eq!(0, vm.registers.data[0]);
+        assert_eq!(0, vm.registers.data[1]);
 
-        vm.perform_next_instr();  // load $4, d0
+        vm.step().unwrap();  // load $4, d0
 
         assert_eq!(1, vm.registers.instr_pointer);
-        assert_eq!(4, vm.registers.data0);
-        assert_eq!(0, vm.registers.data1);
+        assert_eq!(4, vm.registers.data[0]);
+        assert_eq!(0, vm.registers.data[1]);
 
-        vm.perform_next_instr();  // load $3, d0
+        vm.step().unwrap();  // load $3, d0
 
         assert_eq!(2, vm.registers.instr_pointer);
-        assert_eq!(3, vm.registers.data0);
-        assert_eq!(0, vm.registers.data1);
+        assert_eq!(3, vm.registers.data[0]);
+        assert_eq!(0, vm.registers.data[1]);
 
-        vm.perform_next_instr();  // load $2, d0
+        vm.step().unwrap();  // load $2, d0
 
         assert_eq!(3, vm.registers.instr_pointer);
-        assert_eq!(2, vm.registers.data0);
-        assert_eq!(0, vm.registers.data1);
+        assert_eq!(2, vm.registers.data[0]);
+        assert_eq!(0, vm.registers.data[1]);
 
-        vm.perform_next_instr();  // load $1, d1
+        vm.step().unwrap();  // load $1, d1
 
         assert_eq!(4, vm.registers.instr_pointer);
-        assert_eq!(2, vm.registers.data0);
-        assert_eq!(1, vm.registers.data1);
+        assert_eq!(2, vm.registers.data[0]);
+        assert_eq!(1, vm.registers.data[1]);
 
-        vm.perform_next_instr();  // jmp d1
+        vm.step().unwrap();  // jmp d1
 
         assert_eq!(1, vm.registers.instr_pointer);
-        assert_eq!(2, vm.registers.data0);
-        assert_eq!(1, vm.registers.data1);
+        assert_eq!(2, vm.registers.data[0]);
+        assert_eq!(1, vm.registers.data[1]);
 
-        vm.perform_next_instr();  // load $3, d0
+        vm.step().unwrap();  // load $3, d0
 
         assert_eq!(2, vm.registers.instr_pointer);
-        assert_eq!(3, vm.registers.data0);
-        assert_eq!(1, vm.registers.data1);
+        assert_eq!(3, vm.registers.data[0]);
+        assert_eq!(1, vm.registers.data[1]);
     }
 
     #[test]
@@ -548,9 +1098,9 @@ mod tests {
         let mut vm = RuntimeBuilder::new()
             .with_program(program)
             .build();
-        vm.run();
+        vm.run().unwrap();
     
-        assert_eq!(1, vm.registers.data0);
+        assert_eq!(1, vm.registers.data[0]);
     }
 
     #[test]
@@ -565,9 +1115,9 @@ mod tests {
         let mut vm = RuntimeBuilder::new()
             .with_program(program)
             .build();
-        vm.run();
+        vm.run().unwrap();
 
-        assert_eq!(expected_value, vm.registers.data0);
+        assert_eq!(expected_value, vm.registers.data[0]);
     }
 
     #[test]
@@ -582,9 +1132,9 @@ mod tests {
         let mut vm = RuntimeBuilder::new()
             .with_program(program)
             .build();
-        vm.run();
+        vm.run().unwrap();
 
-        assert_eq!(expected_value, vm.registers.data0);
+        assert_eq!(expected_value, vm.registers.data[0]);
     }
 
     #[test]
@@ -597,7 +1147,7 @@ mod tests {
         let mut vm = RuntimeBuilder::new()
             .with_program(program)
             .build();
-        vm.run();
+        vm.run().unwrap();
 
         assert_eq!(449, vm.memory.read(0).unwrap());
     }
@@ -613,8 +1163,1011 @@ mod tests {
         let mut vm = RuntimeBuilder::new()
             .with_program(program)
             .build();
-        vm.run();
+        vm.run().unwrap();
+
+        assert_eq!(449, vm.registers.data[1]);
+    }
+
+    #[test]
+    fn euclidean_algorithm_gcd_from_assembly_source() {
+        let source = "
+            load $230, d1       ; divisor
+            load $448, d0       ; dividend
+            load $0, d2         ; clear remainder location
+        loop:
+            load $0, d3         ; for zero comparison
+            div  d0 d1 d0 d2    ; perform division
+            copy d1, d0         ; divisor is the new dividend
+            copy d2, d1         ; remainder is the new divisor
+            cmp  d2, d3         ; check if remainder is zero
+            jnz  loop
+            halt                ; stop (result is in d0)
+        ";
+        let program = crate::assembler::assemble(source).unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+        vm.run().unwrap();
+
+        assert_eq!(2, vm.registers.data[0]);
+    }
+
+    #[test]
+    fn run_reports_a_normal_halt() {
+        let program = crate::assembler::assemble("load $1, d0\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        assert_eq!(ExitReason::Halted, vm.run().unwrap());
+        assert_eq!(2, vm.registers.instr_pointer);
+    }
+
+    #[test]
+    fn run_reports_division_by_zero_at_the_faulting_instruction() {
+        let program = crate::assembler::assemble("load $1, d0\nload $0, d1\ndiv d0 d1 d2 d3\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        assert!(matches!(vm.run(), Err(Error::DivisionByZero { instr_pointer: 2 })));
+        assert_eq!(2, vm.registers.instr_pointer);
+    }
+
+    #[test]
+    fn run_reports_invalid_registers() {
+        let program = crate::assembler::assemble("halt\nload $1, d19\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_registers(Registers { instr_pointer: 1, ..Registers::default() })
+            .with_program(program)
+            .build();
+
+        assert!(matches!(vm.run(), Err(Error::InvalidRegister { number: 19, instr_pointer: 1 })));
+    }
+
+    #[test]
+    fn run_reports_illegal_opcodes_with_the_offending_word() {
+        let program = vec![
+            0b00000000_0000000000000000000000000000000000000000000001_0000000001i64,    // load $1, d0
+            0b000000000000000000000000000000000000000000000000000000_1000000000i64,     // illegal
+        ];
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        assert!(matches!(vm.run(), Err(Error::IllegalOpcode { instruction: 0b1000000000, instr_pointer: 1 })));
+        assert_eq!(1, vm.registers.instr_pointer);
+    }
+
+    #[test]
+    fn running_off_the_end_of_memory_is_an_error() {
+        let program = crate::assembler::assemble("load $1, d0\ninc d0").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_memory(Memory::new_with_size(16))
+            .with_program(program)
+            .build();
+
+        assert!(matches!(vm.run(), Err(Error::InvalidMemoryAddress { requested_address: 16, upper_bound: 16, instr_pointer: Some(2) })));
+        assert_eq!(2, vm.registers.instr_pointer);
+    }
+
+    #[test]
+    fn jumping_to_a_negative_address_is_an_error() {
+        let program = crate::assembler::assemble("load $5, d1\nsub d0 d1 d2\njmp d2").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        assert_eq!(Err(Error::InvalidInstructionIndex { index: -5i64 as usize, instr_pointer: Some(-5) }), vm.run());
+        assert_eq!(-5, vm.registers.instr_pointer);
+    }
+
+    #[test]
+    fn loading_from_a_bad_address_is_an_error() {
+        let program = crate::assembler::assemble("ldm @100, d0\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_memory(Memory::new_with_size(64))
+            .with_program(program)
+            .build();
+
+        assert!(matches!(vm.run(), Err(Error::InvalidMemoryAddress { requested_address: 100, upper_bound: 64, instr_pointer: Some(0) })));
+        assert_eq!(0, vm.registers.instr_pointer);
+    }
+
+    #[test]
+    fn storing_to_a_bad_address_is_an_error() {
+        let program = crate::assembler::assemble("load $7, d0\nstrm d0, @60\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_memory(Memory::new_with_size(64))
+            .with_program(program)
+            .build();
 
-        assert_eq!(449, vm.registers.data1);
+        assert!(matches!(vm.run(), Err(Error::InvalidMemoryAddress { requested_address: 60, upper_bound: 64, instr_pointer: Some(1) })));
+        assert_eq!(1, vm.registers.instr_pointer);
+    }
+
+    #[test]
+    fn step_returns_the_executed_instruction() {
+        let program = crate::assembler::assemble("load $3, d0\ninc d0\nhalt\ninc d0").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        assert_eq!(Status::Executed(Instruction::Load { value: 3, dest_reg: 0 }), vm.step().unwrap());
+        assert!(vm.running);
+        assert_eq!(Status::Executed(Instruction::Inc { dest: 0 }), vm.step().unwrap());
+        assert_eq!(Status::Exited(ExitReason::Halted), vm.step().unwrap());
+        assert!(!vm.running);
+        assert_eq!(4, vm.registers.data[0]);
+        assert_eq!(3, vm.registers.instr_pointer);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn step_n_stops_after_n_instructions_or_at_halt() {
+        let program = crate::assembler::assemble("inc d0\ninc d0\ninc d0\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        assert_eq!(None, vm.step_n(2).unwrap());
+        assert_eq!(2, vm.registers.data[0]);
+        assert_eq!(2, vm.registers.instr_pointer);
+
+        assert_eq!(Some(ExitReason::Halted), vm.step_n(10).unwrap());
+        assert_eq!(3, vm.registers.data[0]);
+        assert_eq!(4, vm.registers.instr_pointer);
+    }
+
+    #[test]
+    fn step_n_propagates_faults() {
+        let program = crate::assembler::assemble("inc d0\nload $0, d17\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        assert!(matches!(vm.step_n(5), Err(Error::InvalidRegister { number: 17, instr_pointer: 1 })));
+        assert!(!vm.running);
+    }
+
+    #[test]
+    fn run_until_stops_at_a_breakpoint() {
+        let source = "
+                load $3, d1
+            loop:
+                inc d0
+                dec d1
+                cmp d1, d2
+                jnz loop
+                halt
+        ";
+        let program = crate::assembler::assemble(source).unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        let at_loop = |vm: &Runtime| vm.registers.instr_pointer == 1;
+        assert_eq!(None, vm.run_until(at_loop).unwrap());
+        assert_eq!(0, vm.registers.data[0]);
+        assert_eq!(None, vm.run_until(at_loop).unwrap());
+        assert_eq!(1, vm.registers.data[0]);
+        assert_eq!(None, vm.run_until(at_loop).unwrap());
+        assert_eq!(2, vm.registers.data[0]);
+        assert_eq!(Some(ExitReason::Halted), vm.run_until(at_loop).unwrap());
+        assert_eq!(3, vm.registers.data[0]);
+    }
+
+    #[test]
+    fn runaway_programs_run_out_of_fuel() {
+        let program = crate::assembler::assemble("loop:\ninc d0\njmp loop").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_instruction_limit(10)
+            .with_program(program)
+            .build();
+
+        assert_eq!(ExitReason::OutOfFuel, vm.run().unwrap());
+        assert_eq!(Some(0), vm.remaining_fuel());
+        assert_eq!(4, vm.registers.data[0]);
+        assert_eq!(Status::Exited(ExitReason::OutOfFuel), vm.step().unwrap());
+
+        vm.refuel(3);
+        assert_eq!(ExitReason::OutOfFuel, vm.run().unwrap());
+        assert_eq!(5, vm.registers.data[0]);
+    }
+
+    #[test]
+    fn halt_consumes_fuel_and_execution_resumes_after_refuelling() {
+        let program = crate::assembler::assemble("inc d0\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_instruction_limit(1)
+            .with_program(program)
+            .build();
+
+        assert_eq!(ExitReason::OutOfFuel, vm.run().unwrap());
+        assert_eq!(1, vm.registers.data[0]);
+
+        vm.refuel(5);
+        assert_eq!(ExitReason::Halted, vm.run().unwrap());
+        assert_eq!(Some(4), vm.remaining_fuel());
+    }
+
+    #[test]
+    fn runtimes_without_a_limit_have_unlimited_fuel() {
+        let vm = RuntimeBuilder::new()
+            .build();
+
+        assert_eq!(None, vm.remaining_fuel());
+    }
+
+    #[test]
+    fn results_can_be_inspected_through_accessors() {
+        let program = crate::assembler::assemble("load $7, d2\nstrm d2, @72\ncmp d2, d2\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+        vm.run().unwrap();
+
+        assert_eq!(7, vm.register(2).unwrap());
+        assert_eq!(7, vm.register_by_name("d2").unwrap());
+        assert_eq!(4, vm.register_by_name("ip").unwrap());
+        assert_eq!(4, vm.instr_pointer());
+        assert!(vm.flags().zero);
+        assert!(!vm.flags().carry);
+        assert!(!vm.is_running());
+        assert_eq!(vec![0, 7, 0], vm.read_memory(64..88).unwrap());
+        assert!(matches!(vm.register(16), Err(Error::InvalidRegister { number: 16, .. })));
+        assert!(matches!(vm.register_by_name("x0"), Err(Error::UnknownRegister { .. })));
+    }
+
+    #[test]
+    fn snapshots_capture_the_runtime_state() {
+        let program = crate::assembler::assemble("load $3, d1\ndec d1\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_instruction_limit(10)
+            .with_program(program)
+            .build();
+        vm.step().unwrap();
+        let before = vm.snapshot();
+        vm.run().unwrap();
+
+        let expected = RuntimeState {
+            data_registers: vec![0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            instr_pointer: 1,
+            stack_pointer: 2097152,
+            flags: Flags::default(),
+            scratch: 0,
+            mmu: None,
+            running: true,
+            remaining_fuel: Some(9),
+        };
+        assert_eq!(expected, before);
+        assert_eq!([0, 2, 0, 0], vm.snapshot().data_registers[..4]);
+        assert_ne!(before, vm.snapshot());
+
+        assert_eq!(2, run_source("jmp end\nend: halt").snapshot().scratch);
+    }
+
+    #[test]
+    fn pushed_values_are_popped_in_reverse_order() {
+        let program = crate::assembler::assemble("load $1, d0\nload $2, d1\npush d0\npush d1\npop d2\npop d3\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+        let stack_top = vm.stack_pointer();
+
+        vm.step_n(4).unwrap();
+        assert_eq!(stack_top - 16, vm.stack_pointer());
+        assert_eq!(vec![2, 1], vm.read_memory(stack_top as usize - 16..stack_top as usize).unwrap());
+
+        vm.run().unwrap();
+        assert_eq!(2, vm.registers.data[2]);
+        assert_eq!(1, vm.registers.data[3]);
+        assert_eq!(stack_top, vm.stack_pointer());
+    }
+
+    #[test]
+    fn call_and_ret_run_a_subroutine() {
+        let source = "
+                load $3, d0
+                call square
+                call square
+                halt
+            square:
+                mult d0, d0, d0
+                ret
+        ";
+        let program = crate::assembler::assemble(source).unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        assert_eq!(ExitReason::Halted, vm.run().unwrap());
+        assert_eq!(81, vm.registers.data[0]);
+        assert_eq!(vm.memory.stack_region().end as Word, vm.stack_pointer());
+    }
+
+    #[test]
+    fn pushing_onto_a_full_stack_overflows() {
+        let program = crate::assembler::assemble("loop:\npush d0\njmp loop").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_memory(Memory::new_with_size(256).with_stack_size(32))
+            .with_program(program)
+            .build();
+
+        assert!(matches!(vm.run(), Err(Error::StackOverflow { stack_pointer: 224, instr_pointer: 0 })));
+        assert_eq!(224..256, vm.memory.stack_region());
+    }
+
+    #[test]
+    fn popping_an_empty_stack_underflows() {
+        let program = crate::assembler::assemble("push d0\npop d1\npop d1").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        assert!(matches!(vm.run(), Err(Error::StackUnderflow { instr_pointer: 2, .. })));
+
+        let program = crate::assembler::assemble("ret").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+
+        assert!(matches!(vm.run(), Err(Error::StackUnderflow { instr_pointer: 0, .. })));
+    }
+
+    #[test]
+    fn and_or_xor_combine_bits() {
+        let vm = run_source("load $0b1100, d0\nload $0b1010, d1\nand d0, d1, d2\nor d0, d1, d3\nxor d0, d1, d0\nhalt");
+        assert_eq!(0b1000, vm.registers.data[2]);
+        assert_eq!(0b1110, vm.registers.data[3]);
+        assert_eq!(0b0110, vm.registers.data[0]);
+    }
+
+    #[test]
+    fn not_flips_every_bit() {
+        let vm = run_source("load $0b1010, d0\nnot d0, d1\nnot d2, d3\nhalt");
+        assert_eq!(!0b1010, vm.registers.data[1]);
+        assert_eq!(-1, vm.registers.data[3]);
+    }
+
+    #[test]
+    fn shl_shifts_left_modulo_the_word_size() {
+        let vm = run_source("load $3, d0\nload $4, d1\nshl d0, d1, d2\nload $66, d1\nshl d0, d1, d3\nhalt");
+        assert_eq!(48, vm.registers.data[2]);
+        assert_eq!(12, vm.registers.data[3]);
+    }
+
+    #[test]
+    fn shr_fills_with_zeros_and_sar_with_the_sign_bit() {
+        let vm = run_source("load $16, d1\nsub d0, d1, d0\nload $2, d1\nshr d0, d1, d2\nsar d0, d1, d3\nhalt");
+        assert_eq!(-16, vm.registers.data[0]);
+        assert_eq!((-16i64 as u64 >> 2) as Word, vm.registers.data[2]);
+        assert_eq!(-4, vm.registers.data[3]);
+
+        let vm = run_source("load $40, d0\nload $3, d1\nshr d0, d1, d2\nsar d0, d1, d3\nhalt");
+        assert_eq!(5, vm.registers.data[2]);
+        assert_eq!(5, vm.registers.data[3]);
+    }
+
+    #[test]
+    fn arithmetic_updates_flags_so_loops_need_no_compare() {
+        let source = "
+                load $5, d0
+            loop:
+                inc d1
+                dec d0
+                jnz loop
+                halt
+        ";
+        let vm = run_source(source);
+        assert_eq!(5, vm.registers.data[1]);
+        assert_eq!(Flags { zero: true, ..Flags::default() }, vm.registers.flags);
+    }
+
+    #[test]
+    fn add_and_sub_report_carry_and_overflow() {
+        let vm = run_source("load $1, d0\nsub d1, d0, d1\nadd d1, d0, d2\nhalt");
+        assert_eq!(0, vm.registers.data[2]);
+        assert_eq!(Flags { zero: true, sign: false, carry: true, overflow: false }, vm.registers.flags);
+
+        let vm = run_source("load $1, d0\nsub d1, d0, d1\nshr d1, d0, d1\ninc d1\nhalt");
+        assert_eq!(Word::MIN, vm.registers.data[1]);
+        assert_eq!(Flags { zero: false, sign: true, carry: false, overflow: true }, vm.registers.flags);
+    }
+
+    /*
+     * Compares d0 with d1 and records in d2 which of the conditional jumps are taken, one bit
+     * per jump in the order jgt, jlt, jge, jle, ja, jb.
+     */
+    fn taken_jumps(v0: Word, v1: Word) -> Word {
+        let source = "
+                cmp d0, d1
+                jgt gt
+            l1: jlt lt
+            l2: jge ge
+            l3: jle le
+            l4: ja above
+            l5: jb below
+                halt
+            gt:    load $0b100000, d3
+                   or d2, d3, d2
+                   jmp l1
+            lt:    load $0b010000, d3
+                   or d2, d3, d2
+                   jmp l2
+            ge:    load $0b001000, d3
+                   or d2, d3, d2
+                   jmp l3
+            le:    load $0b000100, d3
+                   or d2, d3, d2
+                   jmp l4
+            above: load $0b000010, d3
+                   or d2, d3, d2
+                   jmp l5
+            below: load $0b000001, d3
+                   or d2, d3, d2
+                   halt
+        ";
+        let program = crate::assembler::assemble(source).unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_registers(Registers { data: vec![v0, v1, 0, 0], ..Registers::default() })
+            .with_program(program)
+            .build();
+        vm.run().unwrap();
+        vm.registers.data[2]
+    }
+
+    #[test]
+    fn conditional_jumps_follow_signed_and_unsigned_comparisons() {
+        assert_eq!(0b101010, taken_jumps(5, 3));
+        assert_eq!(0b010101, taken_jumps(3, 5));
+        assert_eq!(0b001100, taken_jumps(4, 4));
+        assert_eq!(0b010110, taken_jumps(-1, 2));
+        assert_eq!(0b101001, taken_jumps(2, -1));
+    }
+
+    #[test]
+    fn jo_and_js_follow_overflow_and_sign() {
+        let source = "
+                load $1, d0
+                sub d1, d0, d1      ; -1: sign set
+                js negative
+                halt
+            negative:
+                load $63, d2
+                shl d0, d2, d2      ; Word::MIN
+                dec d2              ; overflows
+                jo overflowed
+                halt
+            overflowed:
+                load $7, d3
+                halt
+        ";
+        let vm = run_source(source);
+        assert_eq!(Word::MAX, vm.registers.data[2]);
+        assert_eq!(7, vm.registers.data[3]);
+    }
+
+    /*
+     * Leaves Word::MAX in d0, Word::MIN in d1 and -1 in d2.
+     */
+    const EXTREMES: &str = "
+        load $1, d3
+        sub d2, d3, d2
+        shr d2, d3, d0
+        not d0, d1
+    ";
+
+    #[test]
+    fn overflowing_arithmetic_wraps_by_default() {
+        let source = format!("{}\ncopy d0, d2\ninc d2\nadd d0, d0, d3\nhalt", EXTREMES);
+        let (vm, result) = run_with(RuntimeBuilder::new().with_overflow_mode(OverflowMode::Wrap), &source);
+        assert_eq!(ExitReason::Halted, result.unwrap());
+        assert_eq!(Word::MIN, vm.registers.data[2]);
+        assert_eq!(-2, vm.registers.data[3]);
+        assert!(vm.registers.flags.overflow);
+
+        let source = format!("{}\nload $2, d3\nmult d1, d3, d2\nhalt", EXTREMES);
+        let (vm, _) = run_with(RuntimeBuilder::new().with_overflow_mode(OverflowMode::Wrap), &source);
+        assert_eq!(0, vm.registers.data[2]);
+        assert!(vm.registers.flags.overflow);
+    }
+
+    #[test]
+    fn dividing_the_minimum_by_minus_one_wraps() {
+        let source = format!("{}\ndiv d1, d2, d2, d3\nhalt", EXTREMES);
+        let (vm, result) = run_with(RuntimeBuilder::new().with_overflow_mode(OverflowMode::Wrap), &source);
+        assert_eq!(ExitReason::Halted, result.unwrap());
+        assert_eq!(Word::MIN, vm.registers.data[2]);
+        assert_eq!(0, vm.registers.data[3]);
+        assert!(vm.registers.flags.overflow && vm.registers.flags.sign);
+
+        let vm = run_source("load $-7, d0\nload $2, d1\ndiv d0, d1, d2, d3\nhalt");
+        assert_eq!([-3, -1], vm.registers.data[2..4]);
+        assert_eq!(Flags { sign: true, ..Flags::default() }, vm.registers.flags);
+    }
+
+    #[test]
+    fn overflowing_arithmetic_traps_when_asked_to() {
+        let source = format!("{}\ncopy d1, d2\ndec d2\nhalt", EXTREMES);
+        let (vm, result) = run_with(RuntimeBuilder::new().with_overflow_mode(OverflowMode::Trap), &source);
+        assert_eq!(Err(Error::ArithmeticOverflow { instr_pointer: 5 }), result);
+        assert_eq!(Word::MIN, vm.registers.data[2]);
+        assert_eq!(5, vm.registers.instr_pointer);
+
+        let source = format!("{}\ndiv d1, d2, d2, d3\nhalt", EXTREMES);
+        let (_, result) = run_with(RuntimeBuilder::new().with_overflow_mode(OverflowMode::Trap), &source);
+        assert_eq!(Err(Error::ArithmeticOverflow { instr_pointer: 4 }), result);
+
+        let source = format!("{}\nmult d0, d0, d2\nhalt", EXTREMES);
+        let (_, result) = run_with(RuntimeBuilder::new().with_overflow_mode(OverflowMode::Trap), &source);
+        assert_eq!(Err(Error::ArithmeticOverflow { instr_pointer: 4 }), result);
+    }
+
+    #[test]
+    fn carry_alone_does_not_trap() {
+        let (vm, result) = run_with(RuntimeBuilder::new().with_overflow_mode(OverflowMode::Trap), "load $1, d0\nsub d1, d0, d1\nhalt");
+        assert_eq!(ExitReason::Halted, result.unwrap());
+        assert_eq!(-1, vm.registers.data[1]);
+        assert!(vm.registers.flags.carry);
+    }
+
+    #[test]
+    fn euclidean_algorithm_gcd_with_immediate_compare() {
+        let source = "
+            load $230, d1       ; divisor
+            load $448, d0       ; dividend
+        loop:
+            div  d0 d1 d0 d2    ; perform division
+            copy d1, d0         ; divisor is the new dividend
+            copy d2, d1         ; remainder is the new divisor
+            cmpi d2, $0         ; check if remainder is zero
+            jnz  loop
+            halt                ; stop (result is in d0)
+        ";
+        let vm = run_source(source);
+
+        assert_eq!(2, vm.registers.data[0]);
+    }
+
+    #[test]
+    fn immediate_arithmetic_uses_signed_constants_and_sets_flags() {
+        let vm = run_source("load $10, d0\naddi d0, $-15, d1\nsubi d1, $-2, d2\nmuli d2, $-4, d3\nhalt");
+        assert_eq!(-5, vm.registers.data[1]);
+        assert_eq!(-3, vm.registers.data[2]);
+        assert_eq!(12, vm.registers.data[3]);
+        assert!(!vm.registers.flags.sign);
+
+        let vm = run_source("load $10, d0\ncmpi d0, $-1\nhalt");
+        assert!(vm.registers.flags.greater());
+        assert!(vm.registers.flags.below());
+        assert_eq!(10, vm.registers.data[0]);
+    }
+
+    #[test]
+    fn indexed_memory_access_walks_an_array() {
+        let source = "
+                load $808, d1       ; one word past the array base
+                load $1, d0
+            fill:                   ; array[i] = i + 1 for i in 0..5
+                strm d0, [d1 - 8]
+                addi d1, $8, d1
+                inc d0
+                cmpi d0, $6
+                jnz fill
+                load $0, d0
+                load $800, d1
+                load $0, d2
+            sum:
+                ldm [d1], d3
+                add d0, d3, d0
+                addi d1, $8, d1
+                cmpi d1, $840
+                jnz sum
+                ldm [d2+832], d2    ; last element
+                halt
+        ";
+        let vm = run_source(source);
+        assert_eq!(15, vm.registers.data[0]);
+        assert_eq!(5, vm.registers.data[2]);
+        assert_eq!(vec![1, 2, 3, 4, 5], vm.read_memory(800..840).unwrap());
+    }
+
+    #[test]
+    fn indexed_access_below_address_zero_faults() {
+        let program = crate::assembler::assemble("load $1, d1\nldm [d1-2], d0\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new().with_program(program).build();
+        assert!(matches!(vm.run(), Err(Error::InvalidMemoryAddress { .. })));
+        assert_eq!(1, vm.registers.instr_pointer);
+    }
+
+    #[test]
+    fn relative_branches_run_the_same_code_anywhere_in_memory() {
+        let program = crate::assembler::assemble("
+                load $5, d0
+            loop:
+                addi d1, $3, d1
+                dec d0
+                brnz loop
+                halt
+        ").unwrap();
+        for origin in [0, 1000] {
+            let mut memory = Memory::new_with_size(16384);
+            for (offset, word) in program.iter().enumerate() {
+                memory.write_instruction(origin + offset, *word).unwrap();
+            }
+            let mut vm = RuntimeBuilder::new().with_memory(memory).build();
+            vm.registers.instr_pointer = origin as Word;
+            vm.run().unwrap();
+
+            assert_eq!(15, vm.registers.data[1]);
+            assert_eq!(0, vm.registers.data[3]);
+            assert_eq!(origin as Word + 5, vm.registers.instr_pointer);
+        }
+    }
+
+    #[test]
+    fn conditional_branches_follow_the_flags() {
+        let vm = run_source("load $1, d0\ncmpi d0, $2\nbrge $3\nbrlt $3\nload $9, d1\nload $9, d2\nhalt");
+        assert_eq!(0, vm.registers.data[1]);
+        assert_eq!(0, vm.registers.data[2]);
+
+        let vm = run_source("load $2, d0\ncmpi d0, $2\nbrb $2\nbra $2\nbrz $2\nload $9, d1\nhalt");
+        assert_eq!(0, vm.registers.data[1]);
+    }
+
+    #[test]
+    fn programs_can_use_a_larger_register_file() {
+        let program = crate::assembler::assemble("load $6, d31\nload $7, d20\nmult d31, d20, d0\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_registers(Registers::new_with_count(32))
+            .with_program(program.clone())
+            .build();
+        vm.run().unwrap();
+        assert_eq!(42, vm.registers.data[0]);
+
+        let mut vm = RuntimeBuilder::new().with_program(program).build();
+        assert!(matches!(vm.run(), Err(Error::InvalidRegister { number: 31, instr_pointer: 0 })));
+    }
+
+    #[test]
+    fn writing_ip_is_a_computed_jump() {
+        let vm = run_source("load $3, d0\ncopy d0, ip\nload $9, d1\nhalt");
+        assert_eq!(0, vm.registers.data[1]);
+        assert_eq!(4, vm.registers.instr_pointer);
+
+        let vm = run_source("copy ip, d0\naddi d0, $3, d0\ncopy d0, ip\nload $9, d1\nhalt");
+        assert_eq!(0, vm.registers.data[1]);
+    }
+
+    #[test]
+    fn writing_flags_faults_without_changing_them() {
+        let program = crate::assembler::assemble("cmp d0, d0\nload $0, d1\ncopy d1, flags\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new().with_program(program).build();
+        let expected = Error::ReadOnlyRegister { number: Registers::FLAGS, instr_pointer: 2 };
+        assert_eq!(Err(expected), vm.run());
+        assert!(vm.registers.flags.zero);
+        assert_eq!(2, vm.registers.instr_pointer);
+    }
+
+    #[test]
+    fn programs_print_through_devices() {
+        let source = "
+                load $72, d0        ; 'H'
+                out d0, $1
+                load $105, d0       ; 'i'
+                out d0, $1
+                load $10, d0
+                out d0, $1
+                halt
+        ";
+        let capture = Capture::new();
+        let mut vm = RuntimeBuilder::new()
+            .with_device(1, capture.clone())
+            .with_program(crate::assembler::assemble(source).unwrap())
+            .build();
+        vm.run().unwrap();
+
+        assert_eq!("Hi\n", capture.output_string());
+    }
+
+    #[test]
+    fn runtimes_with_devices_can_run_on_another_thread() {
+        let capture = Capture::new();
+        let mut vm = RuntimeBuilder::new()
+            .with_device(1, capture.clone())
+            .with_program(crate::assembler::assemble("load $33, d0\nout d0, $1\nhalt").unwrap())
+            .build();
+        std::thread::spawn(move || vm.run().unwrap()).join().unwrap();
+
+        assert_eq!("!", capture.output_string());
+    }
+
+    #[test]
+    fn programs_read_from_devices_until_the_end_of_input() {
+        let source = "
+            loop:
+                in $2, d0
+                cmpi d0, $-1
+                brz done
+                add d1, d0, d1
+                out d0, $3
+                br loop
+            done:
+                halt
+        ";
+        let output = Capture::new();
+        let mut vm = RuntimeBuilder::new()
+            .with_device(2, Capture::new().with_input(&[4, 5, 6]))
+            .with_device(3, output.clone())
+            .with_program(crate::assembler::assemble(source).unwrap())
+            .build();
+        vm.run().unwrap();
+
+        assert_eq!(15, vm.registers.data[1]);
+        assert_eq!(vec![4, 5, 6], output.output());
+    }
+
+    #[test]
+    fn ports_without_a_device_fault() {
+        let program = crate::assembler::assemble("load $1, d0\nout d0, $9\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_device(1, Capture::new())
+            .with_program(program)
+            .build();
+        assert_eq!(Err(Error::UnmappedPort { port: 9, instr_pointer: 1 }), vm.run());
+    }
+
+    #[test]
+    fn failing_devices_fault_the_instruction() {
+        struct Broken;
+
+        impl Device for Broken {
+            fn read(&mut self) -> std::io::Result<Word> {
+                Err(std::io::Error::other("unplugged"))
+            }
+
+            fn write(&mut self, _: Word) -> std::io::Result<()> {
+                Err(std::io::Error::other("unplugged"))
+            }
+        }
+
+        let program = crate::assembler::assemble("halt\nin $4, d0\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_registers(Registers { instr_pointer: 1, ..Registers::default() })
+            .with_device(4, Broken)
+            .with_program(program)
+            .build();
+        let expected = Error::DeviceFailure { port: 4, message: "unplugged".to_string(), instr_pointer: 1 };
+        assert_eq!(Err(expected), vm.run());
+    }
+
+    #[test]
+    fn load_sign_extends_and_loadh_fills_the_upper_bits() {
+        let vm = run_source("load $-1, d0\nload $-35184372088832, d1\nhalt");
+        assert_eq!(-1, vm.registers.data[0]);
+        assert_eq!(-(1 << 45), vm.registers.data[1]);
+
+        let words: [Word; 4] = [Word::MIN, Word::MAX, 0x0123_4567_89AB_CDEF, -0x0123_4567_89AB_CDEF];
+        for word in words {
+            let low = (word << 18) >> 18;
+            let source = format!("load ${}, d2\nloadh ${}, d2\nhalt", low, word >> 46);
+            assert_eq!(word, run_source(&source).registers.data[2]);
+        }
+    }
+
+    #[test]
+    fn narrow_loads_extend_and_narrow_stores_truncate() {
+        let source = "
+                load $-2, d0
+                strm.w d0, @0x100       ; fe ff ff ff
+                load $0x1234, d1
+                strm.h d1, @0x104       ; 34 12
+                strm.b d0, @0x107       ; fe
+                ldm.b @0x100, d2
+                ldm.bu @0x100, d3
+                ldm.h @0x104, d4
+                ldm.wu @0x100, d5
+                ldm @0x100, d6
+                load $0x102, d7
+                ldm.hu [d7+1], d8       ; ff 34
+                halt
+        ";
+        let vm = run_source(source);
+        assert_eq!(-2, vm.registers.data[2]);
+        assert_eq!(0xFE, vm.registers.data[3]);
+        assert_eq!(0x1234, vm.registers.data[4]);
+        assert_eq!(0xFFFF_FFFE, vm.registers.data[5]);
+        assert_eq!(Word::from_le_bytes([0xFE, 0xFF, 0xFF, 0xFF, 0x34, 0x12, 0x00, 0xFE]), vm.registers.data[6]);
+        assert_eq!(0x34FF, vm.registers.data[8]);
+        assert_eq!(vec![0xFE, 0xFF, 0xFF, 0xFF, 0x34, 0x12, 0x00, 0xFE], vm.memory().read_bytes(0x100..0x108).unwrap());
+    }
+
+    #[test]
+    fn misaligned_accesses_trap_only_when_asked_to() {
+        let source = "load $5, d0\nstrm.h d0, @0x101\nldm.w @0x100, d1\nhalt";
+        let vm = run_source(source);
+        assert_eq!(5 << 8, vm.registers.data[1]);
+
+        let program = crate::assembler::assemble(source).unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_alignment_mode(AlignmentMode::Trap)
+            .with_program(program)
+            .build();
+        assert_eq!(Err(Error::MisalignedAccess { address: 0x101, width: 2, instr_pointer: 1 }), vm.run());
+
+        let program = crate::assembler::assemble("strm.w d0, @0x104\nldm.b @0x107, d0\nldm [d0+0x108], d0\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_alignment_mode(AlignmentMode::Trap)
+            .with_program(program)
+            .build();
+        assert_eq!(ExitReason::Halted, vm.run().unwrap());
+    }
+
+    fn protected() -> RuntimeBuilder {
+        RuntimeBuilder::new()
+            .with_region("code", 0..64, Permissions::READ_EXECUTE)
+            .with_region("data", 64..1024, Permissions::READ_WRITE)
+            .with_region("secret", 1024..1032, Permissions::NONE)
+    }
+
+    #[test]
+    fn protected_regions_fault_guest_accesses() {
+        let (vm, result) = run_with(protected(), "load $7, d0\nstrm d0, @64\nldm @64, d1\npush d1\npop d2\nhalt");
+        assert_eq!(ExitReason::Halted, result.unwrap());
+        assert_eq!(7, vm.registers.data[2]);
+
+        let (vm, result) = run_with(protected(), "load $-1, d0\nstrm.b d0, @8\nhalt");
+        let fault = Error::WriteProtected { address: 8, region: "code".to_string(), instr_pointer: Some(1) };
+        assert_eq!(Err(fault), result);
+        assert_eq!(1, vm.registers.instr_pointer);
+        assert_ne!(-1, vm.memory.read(8).unwrap());
+
+        let (_, result) = run_with(protected(), "ldm.w @1022, d0\nhalt");
+        let fault = Error::ReadProtected { address: 1024, region: "secret".to_string(), instr_pointer: Some(0) };
+        assert_eq!(Err(fault), result);
+    }
+
+    #[test]
+    fn executing_outside_code_faults() {
+        let (vm, result) = run_with(protected(), "load $8, d0\ncall d0");
+        let fault = Error::ExecuteProtected { address: 64, region: "data".to_string(), instr_pointer: Some(8) };
+        assert_eq!(Err(fault), result);
+        assert_eq!(8, vm.registers.instr_pointer);
+    }
+
+    /*
+     * Maps virtual page 0 to the code at physical 0 and virtual page 1 to the frame at
+     * 0x3000, using a page table at 0x1000. Translation is on from index 8, where `source`
+     * starts, and faults go to the handler at index `handler`.
+     */
+    const PAGING_PRELUDE: &str = "
+        load $0b1011, d0
+        strm d0, @0x1000
+        load $0x3007, d0
+        strm d0, @0x1008
+        copy d9, tvec
+        load $0x1001, d0
+        copy d0, ptbr
+    ";
+
+    fn paged(source: &str, handler: Word) -> String {
+        format!("load ${}, d9\n{}{}", handler, PAGING_PRELUDE, source)
+    }
+
+    #[test]
+    fn paging_translates_loads_stores_and_the_stack() {
+        let (vm, result) = run_with(RuntimeBuilder::new().with_mmu(), &paged("load $42, d1\nstrm d1, @0x1010\nldm @0x1010, d2\nload $0x2000, sp\npush d1\npop d3\nhalt", 0));
+        assert_eq!(ExitReason::Halted, result.unwrap());
+        assert_eq!(42, vm.memory.read(0x3010).unwrap());
+        assert_eq!(0, vm.memory.read(0x1010).unwrap());
+        assert_eq!([42, 42], vm.registers.data[2..4]);
+        assert_eq!(42, vm.memory.read(0x3ff8).unwrap());
+    }
+
+    #[test]
+    fn page_faults_without_a_handler_stop_the_runtime() {
+        let (vm, result) = run_with(RuntimeBuilder::new().with_mmu(), &paged("load $1, d1\nstrm d1, @0x10\nhalt", 0));
+        assert_eq!(Err(Error::PageFault { address: 0x10, operation: Operation::Write, instr_pointer: Some(9) }), result);
+        assert_eq!(9, vm.registers.instr_pointer);
+
+        let (_, result) = run_with(RuntimeBuilder::new().with_mmu(), &paged("ldm.w @0x1ffe, d1\nhalt", 0));
+        assert_eq!(Err(Error::PageFault { address: 0x2000, operation: Operation::Read, instr_pointer: Some(8) }), result);
+
+        let (_, result) = run_with(RuntimeBuilder::new().with_mmu(), &paged("ldm.w @0x2ffe, d1\nhalt", 0));
+        assert_eq!(Err(Error::PageFault { address: 0x2ffe, operation: Operation::Read, instr_pointer: Some(8) }), result);
+
+        let (_, result) = run_with(RuntimeBuilder::new().with_mmu(), &paged("load $0x1000, d1\njmp d1", 0));
+        assert_eq!(Err(Error::PageFault { address: 0x8000, operation: Operation::Execute, instr_pointer: Some(0x1000) }), result);
+    }
+
+    #[test]
+    fn page_faults_trap_to_the_guest_handler() {
+        let source = "
+                ldm @0x2008, d2
+                halt
+            handler:
+                copy badaddr, d3
+                copy cause, d4
+                copy epc, d5
+                load $0x3007, d0
+                strm d0, @0x1010
+                load $99, d0
+                strm d0, @0x3008
+                tret
+        ";
+        let (vm, result) = run_with(RuntimeBuilder::new().with_mmu(), &paged(source, 10));
+        assert_eq!(ExitReason::Halted, result.unwrap());
+        assert_eq!(99, vm.registers.data[2]);
+        assert_eq!([0x2008, Mmu::CAUSE_LOAD, 8], vm.registers.data[3..6]);
+        assert!(!vm.registers.mmu.unwrap().in_trap);
+
+        let (mut vm, _) = run_with(RuntimeBuilder::new().with_mmu(), &paged("halt", 0));
+        vm.registers.instr_pointer = 8;
+        vm.registers.mmu.as_mut().unwrap().trap_vector = 10;
+        vm.memory.write_instruction(8, Instruction::LoadMem { src_addr: 0x2000, dest_reg: 0, width: Width::B8, unsigned: false }.encode().unwrap()).unwrap();
+        assert_eq!(Ok(Status::Trapped { cause: Mmu::CAUSE_LOAD, address: 0x2000 }), vm.step());
+        assert_eq!(10, vm.registers.instr_pointer);
+        assert!(vm.is_running());
+    }
+
+    #[test]
+    fn a_sparse_memory_gives_a_large_address_space_cheaply() {
+        let program = crate::assembler::assemble("load $7, d0\npush d0\nloadh $1, d1\nstrm.w d0, [d1+8]\nldm [d1+8], d2\npop d3\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_memory(Memory::sparse_with_size(1 << 48))
+            .with_program(program)
+            .build();
+        assert_eq!(ExitReason::Halted, vm.run().unwrap());
+        assert_eq!(1 << 48, vm.stack_pointer());
+        assert_eq!([7, 1 << 46, 7, 7], vm.registers.data[..4]);
+    }
+
+    #[test]
+    fn mmu_registers_need_an_mmu() {
+        let program = crate::assembler::assemble("load $0x1001, d0\ncopy d0, ptbr\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+        assert_eq!(Err(Error::InvalidRegister { number: Registers::PAGE_TABLE, instr_pointer: 1 }), vm.run());
+
+        let program = crate::assembler::assemble("tret").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+        assert!(matches!(vm.run(), Err(Error::IllegalOpcode { instr_pointer: 0, .. })));
+    }
+
+    #[test]
+    fn regions_and_the_mmu_can_be_declared_before_memory_and_registers() {
+        let program = crate::assembler::assemble("load $0, d0\ncopy d0, ptbr\nstrm d0, @8\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_region("code", 0..64, Permissions::READ_EXECUTE)
+            .with_mmu()
+            .with_memory(Memory::new_with_size(1024))
+            .with_registers(Registers::default())
+            .with_program(program)
+            .build();
+        let fault = Error::WriteProtected { address: 8, region: "code".to_string(), instr_pointer: Some(2) };
+        assert_eq!(Err(fault), vm.run());
+        assert_eq!(Some(Mmu::default()), vm.registers.mmu);
+    }
+
+    #[test]
+    fn values_in_data_registers_survive_label_jumps() {
+        let vm = run_source("load $7, d3\njmp next\nnext: call sub\nhalt\nsub: ret");
+        assert_eq!(7, vm.registers.data[3]);
+    }
+
+    #[test]
+    fn a_faulting_pop_leaves_the_stack_pointer_alone() {
+        let program = crate::assembler::assemble("load $5, d0\npush d0\npop flags\nhalt").unwrap();
+        let mut vm = RuntimeBuilder::new()
+            .with_program(program)
+            .build();
+        assert_eq!(Err(Error::ReadOnlyRegister { number: Registers::FLAGS, instr_pointer: 2 }), vm.run());
+        assert_eq!(2097144, vm.stack_pointer());
+
+        let vm = run_source("load $5, d0\npush d0\npop sp\nhalt");
+        assert_eq!(5, vm.stack_pointer());
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::bind_instead_of_map)]
 pub fn pair_result<T1, T2, E>(
     res1: std::result::Result<T1, E>,
     res2: std::result::Result<T2, E>